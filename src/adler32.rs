@@ -0,0 +1,134 @@
+/// Largest prime below 65536
+const BASE: u32 = 65521;
+/// Most bytes that can be summed before `b` may overflow 32 bits
+const NMAX: usize = 5552;
+
+/// Running Adler-32 checksum
+#[derive(Clone, Copy, Debug)]
+pub struct Adler32 {
+    a: u32,
+    b: u32,
+}
+
+impl Default for Adler32 {
+    fn default() -> Adler32 {
+        Adler32::new()
+    }
+}
+
+impl Adler32 {
+    pub fn new() -> Adler32 {
+        Adler32 { a: 1, b: 0 }
+    }
+
+    /// Carry on from a checksum computed earlier
+    pub fn from_value(adler: u32) -> Adler32 {
+        Adler32 {
+            a: adler & 0xFFFF,
+            b: adler >> 16,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut a = self.a;
+        let mut b = self.b;
+        // Reduce only once per NMAX bytes
+        for chunk in bytes.chunks(NMAX) {
+            let mut blocks = chunk.chunks_exact(16);
+            for block in &mut blocks {
+                // Each byte adds to b once for every byte from itself to the end of
+                // the block, which leaves the inner loop free to vectorise
+                b += 16 * a;
+                for (i, byte) in block.iter().enumerate() {
+                    a += u32::from(*byte);
+                    b += (16 - i as u32) * u32::from(*byte);
+                }
+            }
+            for byte in blocks.remainder() {
+                a += u32::from(*byte);
+                b += a;
+            }
+            a %= BASE;
+            b %= BASE;
+        }
+        self.a = a;
+        self.b = b;
+    }
+
+    pub fn value(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+pub fn adler32(bytes: &[u8]) -> u32 {
+    let mut adler = Adler32::new();
+    adler.update(bytes);
+    adler.value()
+}
+
+/// Adler-32 of two concatenated pieces from the Adler-32 of each and the length of the second
+pub fn adler32_combine(adler1: u32, adler2: u32, len2: u64) -> u32 {
+    let rem = (len2 % u64::from(BASE)) as u32;
+    let a1 = adler1 & 0xFFFF;
+    let b1 = adler1 >> 16;
+    let a2 = adler2 & 0xFFFF;
+    let b2 = adler2 >> 16;
+    let mut a = a1 + a2 + BASE - 1;
+    let mut b = (rem * a1) % BASE + b1 + b2 + BASE - rem;
+    if a >= BASE {
+        a -= BASE;
+    }
+    if a >= BASE {
+        a -= BASE;
+    }
+    if b >= BASE << 1 {
+        b -= BASE << 1;
+    }
+    if b >= BASE {
+        b -= BASE;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::{self, RngCore};
+
+    fn naive(bytes: &[u8]) -> u32 {
+        let mut a = 1;
+        let mut b = 0;
+        for byte in bytes {
+            a = (a + u32::from(*byte)) % BASE;
+            b = (b + a) % BASE;
+        }
+        (b << 16) | a
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        // Worst case for the deferred reductions
+        let ff = vec![0xFF; 3 * NMAX + 17];
+        assert_eq!(adler32(&ff), naive(&ff));
+    }
+
+    #[test]
+    fn incremental_and_combine() {
+        let mut v = vec![0u8; 100_000];
+        rand::thread_rng().fill_bytes(&mut v);
+        let whole = adler32(&v);
+        assert_eq!(whole, naive(&v));
+        let (x, y) = v.split_at(12_345);
+        let mut adler = Adler32::from_value(adler32(x));
+        adler.update(y);
+        assert_eq!(adler.value(), whole);
+        assert_eq!(
+            adler32_combine(adler32(x), adler32(y), y.len() as u64),
+            whole
+        );
+        assert_eq!(adler32_combine(whole, adler32(&[]), 0), whole);
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{BufReader, BufWriter, Error, ErrorKind};
+
+use crate::deflate::inflate;
+use crate::{gzip, zip, zlib};
+
+/// Format `decompress_auto` found a blob to be in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Detected {
+    Gzip,
+    Zlib,
+    /// The first entry of a zip archive
+    Zip,
+    /// Raw deflate, the fallback when nothing else matches
+    Raw,
+}
+
+/// Guess the format of a compressed blob from its first bytes
+pub fn detect(bytes: &[u8]) -> Detected {
+    if bytes.starts_with(&[0x1F, 0x8B]) {
+        Detected::Gzip
+    } else if bytes.starts_with(b"PK\x03\x04") {
+        Detected::Zip
+    } else if zlib::read_header(&mut &bytes[..]).is_ok() {
+        Detected::Zlib
+    } else {
+        Detected::Raw
+    }
+}
+
+fn decode(bytes: &[u8], format: Detected) -> Result<Vec<u8>, Error> {
+    let mut reader = BufReader::new(bytes);
+    let mut writer = BufWriter::new(Vec::new());
+    match format {
+        Detected::Gzip => {
+            gzip::decompress(&mut reader, &mut writer)?;
+        }
+        Detected::Zlib => {
+            zlib::decompress(&mut reader, &mut writer)?;
+        }
+        Detected::Zip => {
+            zip::decompress(&mut reader, &mut writer)?;
+        }
+        Detected::Raw => {
+            inflate(&mut reader, &mut writer)?;
+        }
+    }
+    match writer.into_inner() {
+        Ok(x) => Ok(x),
+        Err(_) => Err(Error::new(ErrorKind::Other, "Can't get the inner output")),
+    }
+}
+
+/// Decompress a blob of unknown format, returning the data and the format it was in
+///
+/// A raw deflate stream can start with what looks like a zlib header, so a blob
+/// that fails to decode as zlib is tried as raw deflate before giving up.
+pub fn decompress_auto(bytes: &[u8]) -> Result<(Vec<u8>, Detected), Error> {
+    let format = detect(bytes);
+    match decode(bytes, format) {
+        Ok(v) => Ok((v, format)),
+        Err(e) if format == Detected::Zlib => match decode(bytes, Detected::Raw) {
+            Ok(v) => Ok((v, Detected::Raw)),
+            Err(_) => Err(e),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    use crate::gzip::GzipMember;
+    use crate::parallel::{deflate_parallel, Format};
+    use crate::zlib::ZlibHeader;
+
+    fn compress(data: &[u8], format: Format) -> Vec<u8> {
+        let mut out = Vec::new();
+        deflate_parallel(&mut &data[..], &mut out, format, 1, &Default::default()).unwrap();
+        out
+    }
+
+    #[test]
+    fn formats() {
+        let data = b"It was the best of times, it was the worst of times".repeat(20);
+        for (format, detected) in [
+            (Format::Gzip(&GzipMember::new()), Detected::Gzip),
+            (Format::Zlib(ZlibHeader::for_level(6)), Detected::Zlib),
+            (Format::Raw, Detected::Raw),
+        ] {
+            let (v, d) = decompress_auto(&compress(&data, format)).unwrap();
+            assert_eq!(d, detected);
+            assert_eq!(v, data);
+        }
+        let (v, d) = decompress_auto(&fs::read("test/dynamic_huffman.zip").unwrap()).unwrap();
+        assert_eq!(d, Detected::Zip);
+        assert!(!v.is_empty());
+        let (_, d) = decompress_auto(&fs::read("test/multiple.gz").unwrap()).unwrap();
+        assert_eq!(d, Detected::Gzip);
+        assert!(decompress_auto(&[0x1F, 0x8B, 8]).is_err());
+    }
+
+    #[test]
+    fn raw_looking_like_zlib() {
+        // A stored block of 29 bytes, whose first two bytes pass the zlib header check,
+        // followed by an empty final stored block
+        let mut raw = vec![0x08, 0x1D, 0x00, 0xE2, 0xFF];
+        raw.extend_from_slice(&[b'z'; 29]);
+        raw.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(detect(&raw), Detected::Zlib);
+        let (v, d) = decompress_auto(&raw).unwrap();
+        assert_eq!(d, Detected::Raw);
+        assert_eq!(v, [b'z'; 29]);
+    }
+}
@@ -0,0 +1,400 @@
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+
+use crate::bitstream::BitWriter;
+use crate::crc32::crc32;
+use crate::deflate::{deflate_block, Options};
+use crate::gzip::{self, parse_extra, ExtraSubfield, GzipMember, Subfield};
+
+/// Uncompressed bytes per block, small enough that even a stored block fits in 64 KiB
+pub const BLOCK_DATA: usize = 0xFF00;
+const MAX_BLOCK: usize = 0x10000;
+/// Header with no other extra subfield than `BC`
+const HEADER_LEN: usize = 18;
+const TRAILER_LEN: usize = 8;
+
+/// Empty block that marks the end of a BGZF file
+pub const EOF_MARKER: [u8; 28] = [
+    0x1F, 0x8B, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1B, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Virtual offset of byte `within` of the block starting at compressed offset `block`
+pub fn virtual_offset(block: u64, within: u16) -> u64 {
+    block << 16 | u64::from(within)
+}
+
+/// Compressed block offset and offset within the uncompressed block
+pub fn split_virtual_offset(voffset: u64) -> (u64, u16) {
+    (voffset >> 16, voffset as u16)
+}
+
+/// Compress `data` into one complete block
+fn compress_block(data: &[u8], options: &Options) -> Result<Vec<u8>, Error> {
+    assert!(data.len() <= BLOCK_DATA);
+    let mut writer = BitWriter::new();
+    deflate_block(&mut writer, &[], data, true, options)?;
+    let mut cdata = writer.finish()?;
+    if cdata.len() > MAX_BLOCK - HEADER_LEN - TRAILER_LEN {
+        // Final stored block
+        let len = data.len() as u16;
+        cdata = vec![1];
+        cdata.extend_from_slice(&len.to_le_bytes());
+        cdata.extend_from_slice(&(!len).to_le_bytes());
+        cdata.extend_from_slice(data);
+    }
+    let bsize = (HEADER_LEN + cdata.len() + TRAILER_LEN - 1) as u16;
+    let mut member = GzipMember::new();
+    member.set_subfields(&[ExtraSubfield::from(Subfield::Bgzf(bsize))])?;
+    let mut block = member.to_bytes();
+    block.extend_from_slice(&cdata);
+    block.extend_from_slice(&crc32(data).to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+/// `.gzi` index: where each block after the first starts, compressed and uncompressed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GziIndex {
+    pub entries: Vec<(u64, u64)>,
+}
+
+impl GziIndex {
+    pub fn read<R: Read>(input: &mut R) -> Result<GziIndex, Error> {
+        let mut qword = [0u8; 8];
+        input.read_exact(&mut qword)?;
+        let count = u64::from_le_bytes(qword);
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            input.read_exact(&mut qword)?;
+            let compressed = u64::from_le_bytes(qword);
+            input.read_exact(&mut qword)?;
+            let uncompressed = u64::from_le_bytes(qword);
+            entries.push((compressed, uncompressed));
+        }
+        Ok(GziIndex { entries })
+    }
+
+    pub fn write<W: Write>(&self, output: &mut W) -> Result<(), Error> {
+        output.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for (compressed, uncompressed) in &self.entries {
+            output.write_all(&compressed.to_le_bytes())?;
+            output.write_all(&uncompressed.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Index an existing BGZF file by walking its blocks
+    pub fn build<R: Read>(input: R) -> Result<GziIndex, Error> {
+        let mut entries = Vec::new();
+        let (mut compressed, mut uncompressed) = (0, 0);
+        for member in gzip::list(input, "")? {
+            if member.uncompressed_size() > 0 && compressed > 0 {
+                entries.push((compressed, uncompressed));
+            }
+            compressed += member.compressed_size();
+            uncompressed += u64::from(member.uncompressed_size());
+        }
+        Ok(GziIndex { entries })
+    }
+
+    /// Virtual offset of uncompressed offset `pos`
+    pub fn virtual_offset(&self, pos: u64) -> Result<u64, Error> {
+        let i = self.entries.partition_point(|&(_, u)| u <= pos);
+        let (compressed, uncompressed) = if i == 0 { (0, 0) } else { self.entries[i - 1] };
+        if pos - uncompressed > u64::from(u16::MAX) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Offset past the end of a BGZF block",
+            ));
+        }
+        Ok(virtual_offset(compressed, (pos - uncompressed) as u16))
+    }
+}
+
+/// Writes BGZF blocks of at most `BLOCK_DATA` bytes each
+///
+/// The file is completed by `finish`, or failing that when the writer is
+/// dropped, where errors and the index are lost.
+pub struct BgzfWriter<W: Write> {
+    // Taken once the EOF marker is written
+    inner: Option<W>,
+    options: Options,
+    pending: Vec<u8>,
+    compressed: u64,
+    uncompressed: u64,
+    index: GziIndex,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, options: &Options) -> BgzfWriter<W> {
+        BgzfWriter {
+            inner: Some(inner),
+            options: *options,
+            pending: Vec::with_capacity(BLOCK_DATA),
+            compressed: 0,
+            uncompressed: 0,
+            index: GziIndex::default(),
+        }
+    }
+
+    /// Virtual offset of the next byte written
+    pub fn virtual_position(&self) -> u64 {
+        virtual_offset(self.compressed, self.pending.len() as u16)
+    }
+
+    /// End the current block so the next byte starts a new one
+    pub fn flush_block(&mut self) -> Result<(), Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let block = compress_block(&self.pending, &self.options)?;
+        self.inner.as_mut().unwrap().write_all(&block)?;
+        if self.compressed > 0 {
+            self.index
+                .entries
+                .push((self.compressed, self.uncompressed));
+        }
+        self.compressed += block.len() as u64;
+        self.uncompressed += self.pending.len() as u64;
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn write_eof(&mut self) -> Result<W, Error> {
+        self.flush_block()?;
+        let mut inner = self.inner.take().unwrap();
+        inner.write_all(&EOF_MARKER)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    /// Write the last block and the EOF marker, returning the output and its index
+    pub fn finish(mut self) -> Result<(W, GziIndex), Error> {
+        let inner = self.write_eof()?;
+        Ok((inner, std::mem::take(&mut self.index)))
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = buf.len().min(BLOCK_DATA - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        if self.pending.len() == BLOCK_DATA {
+            self.flush_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.flush_block()?;
+        self.inner.as_mut().unwrap().flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.write_eof();
+        }
+    }
+}
+
+/// Reads a BGZF file block by block, keeping track of virtual offsets
+pub struct BgzfReader<R: Read> {
+    inner: R,
+    block: Vec<u8>,
+    pos: usize,
+    block_offset: u64,
+    next_offset: u64,
+}
+
+impl<R: Read> BgzfReader<R> {
+    pub fn new(inner: R) -> BgzfReader<R> {
+        BgzfReader {
+            inner,
+            block: Vec::new(),
+            pos: 0,
+            block_offset: 0,
+            next_offset: 0,
+        }
+    }
+
+    /// Virtual offset of the next byte read
+    pub fn virtual_position(&self) -> u64 {
+        virtual_offset(self.block_offset, self.pos as u16)
+    }
+
+    /// Read and decompress the next block, returning false at EOF
+    fn read_block(&mut self) -> Result<bool, Error> {
+        self.block.clear();
+        self.pos = 0;
+        self.block_offset = self.next_offset;
+        let mut header = [0u8; 12];
+        let n = self.inner.read(&mut header)?;
+        if n == 0 {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut header[n..])?;
+        if header[..4] != [0x1F, 0x8B, 8, 4] {
+            return Err(Error::new(ErrorKind::Other, "Not a BGZF block"));
+        }
+        let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        self.inner.read_exact(&mut extra)?;
+        let bsize = parse_extra(&extra)?
+            .iter()
+            .find_map(|sub| match sub.decode() {
+                Some(Subfield::Bgzf(bsize)) => Some(bsize as usize),
+                _ => None,
+            });
+        let size = match bsize {
+            Some(bsize) if bsize + 1 >= header.len() + xlen + TRAILER_LEN => bsize + 1,
+            _ => return Err(Error::new(ErrorKind::Other, "Missing BGZF block size")),
+        };
+        let mut compressed = Vec::with_capacity(size);
+        compressed.extend_from_slice(&header);
+        compressed.extend_from_slice(&extra);
+        compressed.resize(size, 0);
+        self.inner
+            .read_exact(&mut compressed[header.len() + xlen..])?;
+        {
+            let mut reader = BufReader::new(&compressed[..]);
+            let mut writer = BufWriter::new(&mut self.block);
+            gzip::decompress(&mut reader, &mut writer)?;
+        }
+        self.next_offset += size as u64;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> BgzfReader<R> {
+    /// Move to a virtual offset returned by `virtual_position` or a `GziIndex`
+    pub fn seek_virtual(&mut self, voffset: u64) -> Result<(), Error> {
+        let (block, within) = split_virtual_offset(voffset);
+        self.inner.seek(SeekFrom::Start(block))?;
+        self.next_offset = block;
+        self.read_block()?;
+        if usize::from(within) > self.block.len() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Virtual offset past the block",
+            ));
+        }
+        self.pos = usize::from(within);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.block.len() {
+            if !self.read_block()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Cursor;
+
+    fn sample() -> Vec<u8> {
+        (0..200_000u32)
+            .map(|i| (i % 251) as u8 ^ (i / 3000) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample();
+        let mut writer = BgzfWriter::new(Vec::new(), &Options::default());
+        let mut voffsets = Vec::new();
+        for line in data.chunks(10_000) {
+            voffsets.push(writer.virtual_position());
+            writer.write_all(line).unwrap();
+        }
+        let (bgzf, index) = writer.finish().unwrap();
+        assert!(bgzf.ends_with(&EOF_MARKER));
+        assert_eq!(index.entries.len(), data.len() / BLOCK_DATA);
+        assert_eq!(GziIndex::build(&bgzf[..]).unwrap(), index);
+
+        // Plain multi-member gzip to everything else
+        let mut out = Vec::new();
+        {
+            let mut reader = BufReader::new(&bgzf[..]);
+            let mut writer = BufWriter::new(&mut out);
+            gzip::decompress(&mut reader, &mut writer).unwrap();
+        }
+        assert_eq!(out, data);
+
+        let mut reader = BgzfReader::new(Cursor::new(&bgzf));
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+
+        for (i, voffset) in voffsets.iter().enumerate().rev() {
+            reader.seek_virtual(*voffset).unwrap();
+            assert_eq!(reader.virtual_position(), *voffset);
+            let mut line = vec![0u8; 10_000];
+            reader.read_exact(&mut line).unwrap();
+            assert_eq!(line, &data[i * 10_000..(i + 1) * 10_000]);
+        }
+    }
+
+    #[test]
+    fn finish_on_drop() {
+        let data = sample();
+        let mut bgzf = Vec::new();
+        {
+            let mut writer = BgzfWriter::new(&mut bgzf, &Options::level(1));
+            writer.write_all(&data).unwrap();
+        }
+        assert!(bgzf.ends_with(&EOF_MARKER));
+        let mut out = Vec::new();
+        BgzfReader::new(&bgzf[..]).read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn index() {
+        let data = sample();
+        let mut writer = BgzfWriter::new(Vec::new(), &Options::level(1));
+        writer.write_all(&data).unwrap();
+        let (bgzf, index) = writer.finish().unwrap();
+
+        let mut gzi = Vec::new();
+        index.write(&mut gzi).unwrap();
+        assert_eq!(gzi.len(), 8 + 16 * index.entries.len());
+        assert_eq!(GziIndex::read(&mut &gzi[..]).unwrap(), index);
+
+        let mut reader = BgzfReader::new(Cursor::new(&bgzf));
+        for &pos in &[0, 1, 65_279, 65_280, 150_000, 199_999] {
+            reader
+                .seek_virtual(index.virtual_offset(pos).unwrap())
+                .unwrap();
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], data[pos as usize]);
+        }
+    }
+
+    #[test]
+    fn incompressible() {
+        let data: Vec<u8> = (0..BLOCK_DATA as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let block = compress_block(&data, &Options::default()).unwrap();
+        assert!(block.len() <= MAX_BLOCK);
+        let mut reader = BgzfReader::new(&block[..]);
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+}
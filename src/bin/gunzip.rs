@@ -1,25 +1,274 @@
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Error, ErrorKind, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process;
 
 extern crate env_logger;
 extern crate zip;
 use zip::gzip::*;
 
+/// Exit codes, as in GNU gzip
+const OK: i32 = 0;
+const ERROR: i32 = 1;
+const WARNING: i32 = 2;
+
+const USAGE: &str = "Usage: gunzip [-cfklt] [file ...]
+  -c, --stdout  write to standard output and keep the input
+  -f, --force   overwrite existing output files
+  -k, --keep    keep the input files
+  -l, --list    list compressed and uncompressed sizes
+  -t, --test    test the integrity of the input
+  -h, --help    show this help
+With no file, or when file is -, read standard input.";
+
+#[derive(Default)]
+struct Settings {
+    stdout: bool,
+    force: bool,
+    keep: bool,
+    list: bool,
+    test: bool,
+}
+
+/// Running totals for `-l`
+#[derive(Default)]
+struct Totals {
+    files: usize,
+    compressed: u64,
+    uncompressed: u64,
+}
+
+/// Output name for `name`, without its gzip suffix
+fn output_name(name: &str) -> Option<String> {
+    if let Some(stem) = name.strip_suffix(".tgz") {
+        return Some(format!("{}.tar", stem));
+    }
+    [".gz", "-gz", ".z", "-z", "_z"]
+        .iter()
+        .filter_map(|suffix| name.strip_suffix(suffix))
+        .find(|stem| !stem.is_empty() && !stem.ends_with('/'))
+        .map(String::from)
+}
+
+/// Decompress everything from `input` into `output`, returning the exit code
+fn decompress<R: Read, W: Write>(name: &str, input: R, output: W) -> Result<i32, Error> {
+    let mut decoder = GzDecoder::with_policy(input, TrailingPolicy::Tolerant);
+    let mut writer = BufWriter::new(output);
+    io::copy(&mut decoder, &mut writer)?;
+    writer.flush()?;
+    match decoder.trailing() {
+        Some(TrailingData::Garbage(_)) => {
+            eprintln!(
+                "gunzip: {}: decompression OK, trailing garbage ignored",
+                name
+            );
+            Ok(WARNING)
+        }
+        _ => Ok(OK),
+    }
+}
+
+fn ratio(compressed: u64, uncompressed: u64) -> f64 {
+    if uncompressed == 0 {
+        0.0
+    } else {
+        (uncompressed as f64 - compressed as f64) * 100.0 / uncompressed as f64
+    }
+}
+
+fn print_listing(compressed: u64, uncompressed: u64, name: &str) {
+    println!(
+        "{:>19} {:>19} {:>5.1}% {}",
+        compressed,
+        uncompressed,
+        ratio(compressed, uncompressed),
+        name
+    );
+}
+
+/// List the first header and last trailer of `input`, as `gzip -l` does
+fn list<R: Read + io::Seek>(input: R, name: &str, totals: &mut Totals) -> Result<i32, Error> {
+    let member = list_last(input, name)?;
+    if totals.files == 0 {
+        println!("         compressed        uncompressed  ratio uncompressed_name");
+    }
+    let uncompressed = u64::from(member.uncompressed_size());
+    print_listing(member.compressed_size(), uncompressed, name);
+    totals.files += 1;
+    totals.compressed += member.compressed_size();
+    totals.uncompressed += uncompressed;
+    Ok(OK)
+}
+
+fn process_stdin(settings: &Settings, totals: &mut Totals) -> Result<i32, Error> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() && !settings.force {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "compressed data not read from a terminal. Use -f to force decompression.",
+        ));
+    }
+    if settings.list {
+        let mut bytes = Vec::new();
+        stdin.lock().read_to_end(&mut bytes)?;
+        list(Cursor::new(bytes), "stdout", totals)
+    } else if settings.test {
+        decompress("stdin", stdin.lock(), io::sink())
+    } else {
+        decompress("stdin", stdin.lock(), io::stdout().lock())
+    }
+}
+
+fn process_file(name: &str, settings: &Settings, totals: &mut Totals) -> Result<i32, Error> {
+    let metadata = fs::metadata(name)?;
+    if metadata.is_dir() {
+        eprintln!("gunzip: {} is a directory -- ignored", name);
+        return Ok(WARNING);
+    }
+    let out_name = output_name(name);
+    if settings.list {
+        let display = out_name.as_deref().unwrap_or(name);
+        return list(File::open(name)?, display, totals);
+    }
+    if settings.test {
+        return decompress(name, File::open(name)?, io::sink());
+    }
+    if settings.stdout {
+        return decompress(name, File::open(name)?, io::stdout().lock());
+    }
+    let out_name = match out_name {
+        Some(n) => n,
+        None => {
+            eprintln!("gunzip: {}: unknown suffix -- ignored", name);
+            return Ok(WARNING);
+        }
+    };
+    if Path::new(&out_name).exists() && !settings.force {
+        eprintln!("gunzip: {} already exists; not overwritten", out_name);
+        return Ok(WARNING);
+    }
+    let input = File::open(name)?;
+    let output = File::create(&out_name)?;
+    let status = match decompress(name, input, &output) {
+        Ok(status) => status,
+        Err(e) => {
+            drop(output);
+            let _ = fs::remove_file(&out_name);
+            return Err(e);
+        }
+    };
+    // Like gunzip -n: the output takes the compressed file's time and mode
+    output.set_modified(metadata.modified()?)?;
+    fs::set_permissions(&out_name, metadata.permissions())?;
+    if !settings.keep {
+        fs::remove_file(name)?;
+    }
+    Ok(status)
+}
+
 fn main() {
     env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    match args.len() {
-        2 => {
-            let file_name = args[1].as_str();
-            let v = parse(file_name).unwrap();
-            for f in v {
-                match extract(file_name, &f) {
-                    Ok(()) => println!("{} is extracted successfully", file_name),
-                    Err(e) => println!("{:?}", e),
+    let mut settings = Settings::default();
+    let mut files = Vec::new();
+    let mut options_done = false;
+    for arg in env::args().skip(1) {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            files.push(arg);
+            continue;
+        }
+        let flags: Vec<char> = match arg.as_str() {
+            "--" => {
+                options_done = true;
+                continue;
+            }
+            "--stdout" | "--to-stdout" => vec!['c'],
+            "--force" => vec!['f'],
+            "--keep" => vec!['k'],
+            "--list" => vec!['l'],
+            "--test" => vec!['t'],
+            "--help" => vec!['h'],
+            _ if arg.starts_with("--") => vec!['?'],
+            _ => arg.chars().skip(1).collect(),
+        };
+        for flag in flags {
+            match flag {
+                'c' => settings.stdout = true,
+                'f' => settings.force = true,
+                'k' => settings.keep = true,
+                'l' => settings.list = true,
+                't' => settings.test = true,
+                'h' => {
+                    println!("{}", USAGE);
+                    return;
+                }
+                _ => {
+                    eprintln!("gunzip: invalid option '{}'\n{}", arg, USAGE);
+                    process::exit(ERROR);
                 }
             }
         }
-        _ => {
-            println!("Usage: gunzip myfile.zip");
+    }
+    if files.is_empty() {
+        files.push(String::from("-"));
+    }
+
+    let mut exit_code = OK;
+    let mut totals = Totals::default();
+    for name in &files {
+        let result = if name == "-" {
+            process_stdin(&settings, &mut totals)
+        } else {
+            process_file(name, &settings, &mut totals)
+        };
+        match result {
+            Ok(WARNING) if exit_code == OK => exit_code = WARNING,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("gunzip: {}: {}", name, e);
+                exit_code = ERROR;
+            }
+        }
+    }
+    if settings.list && totals.files > 1 {
+        print_listing(totals.compressed, totals.uncompressed, "(totals)");
+    }
+    process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use zip::deflate::Options;
+
+    #[test]
+    fn output_names() {
+        assert_eq!(output_name("a.gz").as_deref(), Some("a"));
+        assert_eq!(output_name("dir/a.tgz").as_deref(), Some("dir/a.tar"));
+        for name in &["a-gz", "a.z", "a-z", "a_z"] {
+            assert_eq!(output_name(name).as_deref(), Some("a"));
         }
+        assert_eq!(output_name("a.txt"), None);
+        assert_eq!(output_name(".gz"), None);
+        assert_eq!(output_name("dir/.gz"), None);
+    }
+
+    #[test]
+    fn trailing_garbage() {
+        let mut compressed =
+            GzipWriter::new(Vec::new(), &GzipMember::new(), &Options::default()).unwrap();
+        compressed.write_all(b"data").unwrap();
+        let mut compressed = compressed.finish().unwrap();
+        let mut out = Vec::new();
+        assert_eq!(decompress("test", &compressed[..], &mut out).unwrap(), OK);
+        assert_eq!(out, b"data");
+
+        compressed.extend_from_slice(b"garbage");
+        let mut out = Vec::new();
+        assert_eq!(
+            decompress("test", &compressed[..], &mut out).unwrap(),
+            WARNING
+        );
+        assert_eq!(out, b"data");
     }
 }
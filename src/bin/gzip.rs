@@ -0,0 +1,343 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+extern crate env_logger;
+extern crate zip;
+use zip::deflate::Options;
+use zip::gzip::*;
+
+/// Exit codes, as in GNU gzip
+const OK: i32 = 0;
+const ERROR: i32 = 1;
+const WARNING: i32 = 2;
+
+const USAGE: &str = "Usage: gzip [-123456789cfhkNnr] [--rsyncable] [file ...]
+  -c, --stdout     write to standard output and keep the input
+  -f, --force      overwrite existing output files and follow symbolic links
+  -k, --keep       keep the input files
+  -N, --name       save the original name and time stamp (default)
+  -n, --no-name    don't save the original name and time stamp
+  -r, --recursive  compress the files in directories
+  -1, --fast       compress faster
+  -9, --best       compress better
+  --rsyncable      make output that rsync can transfer efficiently
+  -h, --help       show this help
+With no file, or when file is -, read standard input.";
+
+struct Settings {
+    level: u8,
+    stdout: bool,
+    force: bool,
+    keep: bool,
+    name: bool,
+    recursive: bool,
+    rsyncable: bool,
+}
+
+impl Settings {
+    fn options(&self) -> Options {
+        let mut options = Options::level(self.level);
+        options.rsyncable = self.rsyncable;
+        options
+    }
+
+    /// Header for a member named `name` and modified at `mtime`, if they are to be saved
+    fn header(&self, name: Option<&[u8]>, mtime: u32) -> Result<GzipMember, Error> {
+        let mut member = GzipMember::new();
+        if self.name {
+            member.set_file_name_bytes(name.unwrap_or(b""))?;
+            member.set_mtime(mtime);
+        }
+        member.set_extra_flags(match self.level {
+            1 => ExtraFlags::Fastest,
+            9 => ExtraFlags::Maximum,
+            _ => ExtraFlags::Ignored,
+        });
+        member.set_os(if cfg!(unix) { OS::UNIX } else { OS::Unknown });
+        Ok(member)
+    }
+}
+
+fn compress_stream<R: Read, W: Write>(
+    input: R,
+    output: W,
+    member: &GzipMember,
+    options: &Options,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+    compress(&mut reader, &mut writer, member, options)?;
+    writer.flush()
+}
+
+fn process_stdin(settings: &Settings) -> Result<i32, Error> {
+    let stdout = io::stdout();
+    if stdout.is_terminal() && !settings.force {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "compressed data not written to a terminal. Use -f to force compression.",
+        ));
+    }
+    let member = settings.header(None, 0)?;
+    compress_stream(
+        io::stdin().lock(),
+        stdout.lock(),
+        &member,
+        &settings.options(),
+    )?;
+    Ok(OK)
+}
+
+fn process_path(path: &Path, settings: &Settings) -> Result<i32, Error> {
+    let mut metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        if !settings.force {
+            eprintln!("gzip: {} is a symbolic link -- ignored", path.display());
+            return Ok(WARNING);
+        }
+        metadata = fs::metadata(path)?;
+    }
+    if metadata.is_dir() {
+        if !settings.recursive {
+            eprintln!("gzip: {} is a directory -- ignored", path.display());
+            return Ok(WARNING);
+        }
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, Error>>()?;
+        entries.sort();
+        let mut status = OK;
+        for entry in entries {
+            match process_path(&entry, settings) {
+                Ok(WARNING) if status == OK => status = WARNING,
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("gzip: {}: {}", entry.display(), e);
+                    status = ERROR;
+                }
+            }
+        }
+        return Ok(status);
+    }
+    if path.as_os_str().as_encoded_bytes().ends_with(b".gz") && !settings.force {
+        eprintln!(
+            "gzip: {} already has .gz suffix -- unchanged",
+            path.display()
+        );
+        return Ok(WARNING);
+    }
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| u32::try_from(t.as_secs()).unwrap_or(0));
+    let base_name = path.file_name().map(|n| n.as_encoded_bytes());
+    let member = settings.header(base_name, mtime)?;
+    if settings.stdout {
+        let stdout = io::stdout();
+        if stdout.is_terminal() && !settings.force {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "compressed data not written to a terminal. Use -f to force compression.",
+            ));
+        }
+        compress_stream(
+            File::open(path)?,
+            stdout.lock(),
+            &member,
+            &settings.options(),
+        )?;
+        return Ok(OK);
+    }
+    let mut out_name = path.as_os_str().to_owned();
+    out_name.push(".gz");
+    let out_name = PathBuf::from(out_name);
+    if out_name.exists() && !settings.force {
+        eprintln!(
+            "gzip: {} already exists; not overwritten",
+            out_name.display()
+        );
+        return Ok(WARNING);
+    }
+    let input = File::open(path)?;
+    let output = File::create(&out_name)?;
+    if let Err(e) = compress_stream(input, &output, &member, &settings.options()) {
+        drop(output);
+        let _ = fs::remove_file(&out_name);
+        return Err(e);
+    }
+    output.set_modified(metadata.modified()?)?;
+    fs::set_permissions(&out_name, metadata.permissions())?;
+    if !settings.keep {
+        fs::remove_file(path)?;
+    }
+    Ok(OK)
+}
+
+fn main() {
+    env_logger::init();
+    let mut settings = Settings {
+        level: 6,
+        stdout: false,
+        force: false,
+        keep: false,
+        name: true,
+        recursive: false,
+        rsyncable: false,
+    };
+    let mut files = Vec::new();
+    let mut options_done = false;
+    for arg in env::args().skip(1) {
+        if options_done || arg == "-" || !arg.starts_with('-') {
+            files.push(arg);
+            continue;
+        }
+        let flags: Vec<char> = match arg.as_str() {
+            "--" => {
+                options_done = true;
+                continue;
+            }
+            "--rsyncable" => {
+                settings.rsyncable = true;
+                continue;
+            }
+            "--stdout" | "--to-stdout" => vec!['c'],
+            "--force" => vec!['f'],
+            "--keep" => vec!['k'],
+            "--name" => vec!['N'],
+            "--no-name" => vec!['n'],
+            "--recursive" => vec!['r'],
+            "--fast" => vec!['1'],
+            "--best" => vec!['9'],
+            "--help" => vec!['h'],
+            _ if arg.starts_with("--") => vec!['?'],
+            _ => arg.chars().skip(1).collect(),
+        };
+        for flag in flags {
+            match flag {
+                '1'..='9' => settings.level = flag as u8 - b'0',
+                'c' => settings.stdout = true,
+                'f' => settings.force = true,
+                'k' => settings.keep = true,
+                'N' => settings.name = true,
+                'n' => settings.name = false,
+                'r' => settings.recursive = true,
+                'h' => {
+                    println!("{}", USAGE);
+                    return;
+                }
+                _ => {
+                    eprintln!("gzip: invalid option '{}'\n{}", arg, USAGE);
+                    process::exit(ERROR);
+                }
+            }
+        }
+    }
+    if files.is_empty() {
+        files.push(String::from("-"));
+    }
+
+    let mut exit_code = OK;
+    for name in &files {
+        let result = if name == "-" {
+            process_stdin(&settings)
+        } else {
+            process_path(Path::new(name), &settings)
+        };
+        match result {
+            Ok(WARNING) if exit_code == OK => exit_code = WARNING,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("gzip: {}: {}", name, e);
+                exit_code = ERROR;
+            }
+        }
+    }
+    process::exit(exit_code);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings(name: bool) -> Settings {
+        Settings {
+            level: 9,
+            stdout: false,
+            force: false,
+            keep: false,
+            name,
+            recursive: false,
+            rsyncable: false,
+        }
+    }
+
+    /// Compress `data` with the header `settings` builds and read it back
+    fn round_trip(settings: &Settings, data: &[u8]) -> GzipMember {
+        let member = settings.header(Some(b"notes.txt"), 1_600_000_000).unwrap();
+        let mut compressed = Vec::new();
+        compress_stream(data, &mut compressed, &member, &settings.options()).unwrap();
+        let mut out = Vec::new();
+        let (mut members, _) = decompress_with(
+            &mut BufReader::new(&compressed[..]),
+            &mut BufWriter::new(&mut out),
+            TrailingPolicy::Strict,
+        )
+        .unwrap();
+        assert_eq!(out, data);
+        members.remove(0)
+    }
+
+    #[test]
+    fn save_name() {
+        let data = b"Some text\n".repeat(1000);
+        let member = round_trip(&settings(true), &data);
+        assert_eq!(member.file_name(), "notes.txt");
+        assert_eq!(
+            member.mtime(),
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000))
+        );
+        assert_eq!(member.compression_hint(), ExtraFlags::Maximum);
+    }
+
+    #[test]
+    fn no_name() {
+        let data = b"Some text\n".repeat(1000);
+        let member = round_trip(&settings(false), &data);
+        assert_eq!(member.file_name(), "");
+        assert_eq!(member.mtime(), None);
+    }
+
+    #[test]
+    fn raw_name() {
+        let member = settings(true).header(Some(b"caf\xe9"), 0).unwrap();
+        assert_eq!(member.file_name_bytes(), b"caf\xe9");
+        assert!(settings(true).header(Some(b"a\0b"), 0).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_skipped() {
+        let dir = std::env::temp_dir().join(format!("gzip-symlinks-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/a.txt"), b"text").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("sub/loop")).unwrap();
+        std::os::unix::fs::symlink("a.txt", dir.join("sub/link")).unwrap();
+        let mut settings = settings(true);
+        settings.recursive = true;
+        settings.keep = true;
+        assert_eq!(process_path(&dir, &settings).unwrap(), WARNING);
+        assert!(dir.join("sub/a.txt.gz").exists());
+        assert!(!dir.join("sub/link.gz").exists());
+
+        settings.recursive = false;
+        settings.force = true;
+        assert_eq!(process_path(&dir.join("sub/link"), &settings).unwrap(), OK);
+        assert!(dir.join("sub/link.gz").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,41 @@
-use std::io::{self, Read};
+use std::io::{self, BufRead, Write};
 
 pub type Bits = u16;
 
-pub struct BitReader<'a, R: Read + 'a> {
+/// Order bits are packed into bytes
+///
+/// Huffman codes are sent starting from their most significant bit either way,
+/// so in an LSB-first stream they go through `reverse`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BitOrder {
+    /// Least significant bit first, as in deflate
+    #[default]
+    Lsb,
+    /// Most significant bit first, as in bzip2, JPEG or the older PKWARE methods
+    Msb,
+}
+
+/// Reads bits from a buffered reader through a 64-bit accumulator
+///
+/// Bytes are copied from the reader's buffer several at a time but only consumed
+/// from it once their bits are used, so whatever follows the bit stream can still
+/// be read from the reader after this one is dropped.
+pub struct BitReader<'a, R: BufRead + 'a> {
     buf: &'a mut R,
+    acc: u64,
     bits: u8,
-    acc: u32,
+    order: BitOrder,
+    // Bytes of the reader's buffer copied into the accumulator but not consumed yet
+    peeked: usize,
+    position: u64,
+}
+
+/// Bits a suspended `BitReader` had already taken from its reader
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BitState {
+    acc: u64,
+    bits: u8,
+    position: u64,
 }
 
 pub fn reverse(a: Bits, n: u8) -> Bits {
@@ -33,93 +63,354 @@ pub fn reverse(a: Bits, n: u8) -> Bits {
     v >> (16 - n)
 }
 
-impl<'a, R: Read> BitReader<'a, R> {
-    pub fn new(buf: &'a mut R) -> BitReader<R> {
+impl<'a, R: BufRead> BitReader<'a, R> {
+    pub fn new(buf: &'a mut R) -> BitReader<'a, R> {
+        BitReader::with_order(buf, BitOrder::Lsb)
+    }
+
+    pub fn with_order(buf: &'a mut R, order: BitOrder) -> BitReader<'a, R> {
         BitReader {
             buf,
-            bits: 0,
             acc: 0,
+            bits: 0,
+            order,
+            peeked: 0,
+            position: 0,
         }
     }
 
-    //order: true for LSB and false for MSB (Huffman codes)
-    pub fn read_bits(&mut self, n: u8, order: bool) -> Result<Bits, io::Error> {
-        assert!(n <= 16);
-        let mut bytes: [u8; 1] = [0; 1];
+    /// Carry on where a reader suspended with `into_state` left off
+    pub fn resume(buf: &'a mut R, order: BitOrder, state: BitState) -> BitReader<'a, R> {
+        BitReader {
+            buf,
+            acc: state.acc,
+            bits: state.bits,
+            order,
+            peeked: 0,
+            position: state.position,
+        }
+    }
+
+    /// Stop reading, keeping the bits that can't be given back to the reader
+    pub fn into_state(self) -> BitState {
+        // Same as what drop gives back
+        let unused = usize::from(self.bits / 8).min(self.peeked) as u8;
+        let bits = self.bits - 8 * unused;
+        let acc = match self.order {
+            BitOrder::Lsb => self.acc,
+            BitOrder::Msb => self.acc >> (8 * unused),
+        };
+        let mask = 1u64
+            .checked_shl(u32::from(bits))
+            .map_or(u64::MAX, |m| m - 1);
+        BitState {
+            acc: acc & mask,
+            bits,
+            position: self.position,
+        }
+    }
+
+    pub fn order(&self) -> BitOrder {
+        self.order
+    }
+
+    /// Number of bits read so far
+    pub fn bit_position(&self) -> u64 {
+        self.position
+    }
+
+    /// Make at least `n` bits available, failing if the input ends first
+    fn fill(&mut self, n: u8) -> Result<(), io::Error> {
         while self.bits < n {
-            self.buf.read_exact(&mut bytes)?;
-            let byte = bytes[0];
-            self.acc |= (u32::from(byte)) << self.bits;
-            self.bits += 8;
+            let window = self.buf.fill_buf()?;
+            if self.peeked == window.len() {
+                // Bytes already in the accumulator have to go before the reader refills
+                self.buf.consume(self.peeked);
+                self.peeked = 0;
+                if self.buf.fill_buf()?.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Bit stream ended early",
+                    ));
+                }
+                continue;
+            }
+            let room = usize::from((64 - self.bits) / 8);
+            let k = room.min(window.len() - self.peeked);
+            for byte in &window[self.peeked..self.peeked + k] {
+                let byte = u64::from(*byte);
+                self.acc = match self.order {
+                    BitOrder::Lsb => self.acc | byte << self.bits,
+                    BitOrder::Msb => self.acc << 8 | byte,
+                };
+                self.bits += 8;
+            }
+            self.peeked += k;
+        }
+        Ok(())
+    }
+
+    /// Look at the next `n` bits without reading them, the first bit lowest in an
+    /// LSB-first stream and highest in an MSB-first one
+    ///
+    /// Near the end of the input this may pull in bytes that are never consumed,
+    /// so decoders that must leave the reader at the exact end use `read_bits`.
+    pub fn peek_bits(&mut self, n: u8) -> Result<u32, io::Error> {
+        assert!(n <= 32);
+        self.fill(n)?;
+        let mask = (1u64 << n) - 1;
+        let v = match self.order {
+            BitOrder::Lsb => self.acc & mask,
+            BitOrder::Msb => (self.acc >> (self.bits - n)) & mask,
+        };
+        Ok(v as u32)
+    }
+
+    /// Drop `n` bits made available by `peek_bits`
+    pub fn consume(&mut self, n: u8) {
+        assert!(n <= self.bits);
+        if self.order == BitOrder::Lsb {
+            self.acc = self.acc.checked_shr(u32::from(n)).unwrap_or(0);
         }
-        let res = self.acc & ((1 << n) - 1);
-        self.acc >>= n;
         self.bits -= n;
-        if order {
-            Ok(res as Bits)
+        self.position += u64::from(n);
+    }
+
+    //order: true for LSB and false for MSB (Huffman codes)
+    pub fn read_bits(&mut self, n: u8, order: bool) -> Result<Bits, io::Error> {
+        assert!(n <= 16);
+        let res = self.peek_bits(n)? as Bits;
+        self.consume(n);
+        // An MSB-first stream holds values with their first bit on top already
+        if order == (self.order == BitOrder::Lsb) {
+            Ok(res)
         } else {
-            Ok(reverse(res as Bits, n))
+            Ok(reverse(res, n))
+        }
+    }
+
+    /// Read up to 32 bits as a number, in the stream's natural order
+    pub fn read_u32(&mut self, n: u8) -> Result<u32, io::Error> {
+        let res = self.peek_bits(n)?;
+        self.consume(n);
+        Ok(res)
+    }
+
+    /// Discard the remaining bits of a partially read byte
+    pub fn align_to_byte(&mut self) {
+        let n = self.bits % 8;
+        self.consume(n);
+    }
+
+    /// Fill `out` with whole bytes, which must start on a byte boundary
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), io::Error> {
+        assert!(self.bits % 8 == 0);
+        let mut i = 0;
+        while self.bits > 0 && i < out.len() {
+            out[i] = self.read_u32(8)? as u8;
+            i += 1;
+        }
+        if i == out.len() {
+            return Ok(());
         }
+        // The accumulator is empty, so go straight to the reader
+        self.buf.consume(self.peeked);
+        self.peeked = 0;
+        self.buf.read_exact(&mut out[i..])?;
+        self.position += (out.len() - i) as u64 * 8;
+        Ok(())
     }
 }
 
-#[derive(Default)]
-pub struct BitWriter {
+impl<'a, R: BufRead> Drop for BitReader<'a, R> {
+    /// Give back the whole bytes that were peeked at but not read
+    fn drop(&mut self) {
+        let unused = usize::from(self.bits / 8).min(self.peeked);
+        self.buf.consume(self.peeked - unused);
+    }
+}
+
+/// Size of the staging buffer in front of the inner writer
+const WRITE_BUF_SIZE: usize = 8192;
+
+/// Packs bits through a 64-bit accumulator and writes whole words to `W`
+///
+/// With the default `Vec<u8>` the bytes stay in memory until `finish` returns them.
+pub struct BitWriter<W: Write = Vec<u8>> {
+    inner: W,
+    buf: Vec<u8>,
+    acc: u64,
     bits: u8,
-    acc: u32,
+    order: BitOrder,
+    // Bytes moved out of the accumulator so far
+    bytes: u64,
+}
+
+impl BitWriter<Vec<u8>> {
+    pub fn new() -> BitWriter<Vec<u8>> {
+        BitWriter::with_order(BitOrder::Lsb)
+    }
+
+    pub fn with_order(order: BitOrder) -> BitWriter<Vec<u8>> {
+        BitWriter::from_writer(Vec::new(), order)
+    }
+}
+
+impl Default for BitWriter<Vec<u8>> {
+    fn default() -> Self {
+        BitWriter::new()
+    }
 }
 
-impl BitWriter {
-    pub fn new() -> BitWriter {
-        BitWriter { bits: 0, acc: 0 }
+impl<W: Write> BitWriter<W> {
+    pub fn from_writer(inner: W, order: BitOrder) -> BitWriter<W> {
+        BitWriter {
+            inner,
+            buf: Vec::with_capacity(WRITE_BUF_SIZE + 8),
+            acc: 0,
+            bits: 0,
+            order,
+            bytes: 0,
+        }
+    }
+
+    pub fn order(&self) -> BitOrder {
+        self.order
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Number of bits written so far, including padding
+    pub fn bit_count(&self) -> u64 {
+        self.bytes * 8 + u64::from(self.bits)
+    }
+
+    pub fn is_aligned(&self) -> bool {
+        self.bits % 8 == 0
+    }
+
+    fn put_word(&mut self, word: u64) -> Result<(), io::Error> {
+        match self.order {
+            BitOrder::Lsb => self.buf.extend_from_slice(&word.to_le_bytes()),
+            BitOrder::Msb => self.buf.extend_from_slice(&word.to_be_bytes()),
+        }
+        self.bytes += 8;
+        if self.buf.len() >= WRITE_BUF_SIZE {
+            self.inner.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
     }
 
-    pub fn write_bits(&mut self, b: Bits, n: u8) -> Vec<u8> {
+    /// Write the low `n` bits of `b`, lowest first in an LSB-first stream
+    /// and highest first in an MSB-first one
+    pub fn write_bits(&mut self, b: Bits, n: u8) -> Result<(), io::Error> {
         assert!(n <= 16);
-        assert!(b <= 1 << n);
-        let c = b; //if order { b } else { reverse(b, n) };
-        self.acc |= (u32::from(c)) << self.bits;
-        self.bits += n;
-        let nb = self.bits / 8;
-        let mut bytes = vec![0 as u8; nb as usize];
-        if nb > 0 {
-            for b in bytes.iter_mut() {
-                *b = (self.acc & 0xFF) as u8;
-                self.acc >>= 8;
+        let c = u64::from(b);
+        assert!(c < 1 << n);
+        if self.bits + n < 64 {
+            self.acc = match self.order {
+                BitOrder::Lsb => self.acc | c << self.bits,
+                BitOrder::Msb => self.acc << n | c,
+            };
+            self.bits += n;
+            return Ok(());
+        }
+        // Fill the accumulator up to a whole word and keep what is left over
+        let rest = self.bits + n - 64;
+        let word = match self.order {
+            BitOrder::Lsb => self.acc | c << self.bits,
+            BitOrder::Msb => self.acc << (n - rest) | c >> rest,
+        };
+        self.acc = match self.order {
+            BitOrder::Lsb => c >> (n - rest),
+            BitOrder::Msb => c & ((1 << rest) - 1),
+        };
+        self.bits = rest;
+        self.put_word(word)
+    }
+
+    /// Write up to 32 bits of `v`, in the same order as `write_bits`
+    pub fn write_u32(&mut self, v: u32, n: u8) -> Result<(), io::Error> {
+        assert!(n <= 32);
+        if n <= 16 {
+            return self.write_bits(v as Bits, n);
+        }
+        let (low, high) = ((v & 0xFFFF) as Bits, (v >> 16) as Bits);
+        match self.order {
+            BitOrder::Lsb => {
+                self.write_bits(low, 16)?;
+                self.write_bits(high, n - 16)
+            }
+            BitOrder::Msb => {
+                self.write_bits(high, n - 16)?;
+                self.write_bits(low, 16)
             }
-            self.bits -= nb * 8;
         }
-        bytes
     }
 
-    pub fn flush(&mut self) -> Option<u8> {
-        if self.bits > 0 {
-            assert!(self.bits < 8);
-            self.bits = 0;
-            let byte = self.acc as u8;
-            self.acc = 0;
-            Some(byte)
-        } else {
-            None
+    /// Pad with zero bits up to the next byte boundary and move the accumulator
+    /// into the buffer
+    pub fn align_to_byte(&mut self) {
+        let n = usize::from(self.bits.div_ceil(8));
+        if self.order == BitOrder::Msb && self.bits % 8 != 0 {
+            self.acc <<= 8 - self.bits % 8;
         }
+        for i in 0..n {
+            let byte = match self.order {
+                BitOrder::Lsb => self.acc >> (8 * i),
+                BitOrder::Msb => self.acc >> (8 * (n - 1 - i)),
+            };
+            self.buf.push(byte as u8);
+        }
+        self.bytes += n as u64;
+        self.acc = 0;
+        self.bits = 0;
+    }
+
+    /// Write a run of whole bytes, which must start on a byte boundary
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), io::Error> {
+        assert!(self.is_aligned());
+        self.align_to_byte();
+        self.bytes += bytes.len() as u64;
+        if self.buf.len() + bytes.len() < WRITE_BUF_SIZE {
+            self.buf.extend_from_slice(bytes);
+            return Ok(());
+        }
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.inner.write_all(bytes)
+    }
+
+    /// Pad to a byte boundary and hand everything over to the inner writer
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.align_to_byte();
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.inner.flush()
+    }
+
+    /// Flush and return the inner writer
+    pub fn finish(mut self) -> Result<W, io::Error> {
+        self.flush()?;
+        Ok(self.inner)
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::io::{BufReader, Cursor};
+    use std::io::{BufReader, Cursor, Read};
 
     #[test]
     fn basics() {
         let mut writer = BitWriter::new();
-        let mut vec = writer.write_bits(0x5A5A, 15);
-        assert!(vec.len() == 1);
-        vec.extend(writer.write_bits(0x3AA5, 15).iter());
-        assert!(vec.len() == 3);
-        writer.flush().map(|c| {
-            vec.push(c);
-        });
+        writer.write_bits(0x5A5A, 15).unwrap();
+        writer.write_bits(0x3AA5, 15).unwrap();
+        assert_eq!(writer.bit_count(), 30);
+        let vec = writer.finish().unwrap();
         assert!(vec.len() == 4);
         let mut input = BufReader::new(Cursor::new(vec));
         let mut reader = BitReader::new(&mut input);
@@ -128,4 +419,142 @@ mod test {
         let second = reader.read_bits(15, true).unwrap();
         assert_eq!(second, 0x3AA5);
     }
+
+    #[test]
+    fn msb_first() {
+        let mut writer = BitWriter::with_order(BitOrder::Msb);
+        writer.write_bits(0b101, 3).unwrap();
+        writer.write_bits(0x1234, 16).unwrap();
+        let vec = writer.finish().unwrap();
+        assert_eq!(vec, [0b1010_0010, 0b0100_0110, 0b1000_0000]);
+        let mut input = BufReader::new(Cursor::new(vec));
+        let mut reader = BitReader::with_order(&mut input, BitOrder::Msb);
+        assert_eq!(reader.read_bits(3, false).unwrap(), 0b101);
+        assert_eq!(reader.read_bits(16, true).unwrap(), reverse(0x1234, 16));
+        reader.align_to_byte();
+        assert!(reader.read_bits(1, false).is_err());
+    }
+
+    #[test]
+    fn words_and_bytes() {
+        for order in [BitOrder::Lsb, BitOrder::Msb] {
+            let mut output = Vec::new();
+            {
+                let mut writer = BitWriter::from_writer(&mut output, order);
+                for i in 0..10_000u16 {
+                    writer.write_bits(i % 128, 7 + (i % 10) as u8).unwrap();
+                }
+                writer.align_to_byte();
+                let aligned = writer.bit_count();
+                assert_eq!(aligned % 8, 0);
+                writer.write_bytes(&[1, 2, 3]).unwrap();
+                writer.write_bytes(&vec![7; 3 * WRITE_BUF_SIZE]).unwrap();
+                writer.write_bits(1, 1).unwrap();
+                assert_eq!(
+                    writer.bit_count(),
+                    aligned + (3 + 3 * WRITE_BUF_SIZE as u64) * 8 + 1
+                );
+                writer.flush().unwrap();
+            }
+            let mut input = BufReader::new(Cursor::new(output));
+            let mut reader = BitReader::with_order(&mut input, order);
+            let lsb = order == BitOrder::Lsb;
+            for i in 0..10_000u16 {
+                assert_eq!(reader.read_bits(7 + (i % 10) as u8, lsb).unwrap(), i % 128);
+            }
+            reader.align_to_byte();
+            assert_eq!(reader.read_bits(8, lsb).unwrap(), 1);
+            assert_eq!(
+                reader.read_bits(16, lsb).unwrap(),
+                if lsb { 0x0302 } else { 0x0203 }
+            );
+            for _ in 0..3 * WRITE_BUF_SIZE {
+                assert_eq!(reader.read_bits(8, lsb).unwrap(), 7);
+            }
+            assert_eq!(reader.read_bits(1, lsb).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn peek_and_position() {
+        let bytes = [0b1010_1100u8, 0xFF, 0x12, 0x34, 0x56];
+        let mut input = &bytes[..];
+        {
+            let mut reader = BitReader::new(&mut input);
+            assert_eq!(reader.peek_bits(4).unwrap(), 0b1100);
+            reader.consume(3);
+            assert_eq!(reader.bit_position(), 3);
+            assert_eq!(reader.read_bits(2, true).unwrap(), 0b01);
+            reader.align_to_byte();
+            assert_eq!(reader.bit_position(), 8);
+            assert_eq!(reader.read_u32(24).unwrap(), 0x3412FF);
+            assert_eq!(reader.bit_position(), 32);
+        }
+        // Bytes peeked at but not read stay in the underlying reader
+        assert_eq!(input, [0x56]);
+
+        let mut input = BufReader::with_capacity(2, &bytes[..]);
+        let mut out = [0u8; 3];
+        {
+            let mut reader = BitReader::new(&mut input);
+            assert_eq!(reader.read_bits(8, true).unwrap(), 0b1010_1100);
+            reader.read_bytes(&mut out).unwrap();
+            assert_eq!(reader.bit_position(), 32);
+        }
+        assert_eq!(out, [0xFF, 0x12, 0x34]);
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, [0x56]);
+    }
+
+    #[test]
+    fn bzip2_header() {
+        // Stream header and end-of-stream marker of an empty bzip2 file
+        let empty = [
+            0x42, 0x5A, 0x68, 0x39, 0x17, 0x72, 0x45, 0x38, 0x50, 0x90, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let mut input = &empty[..];
+        let mut reader = BitReader::with_order(&mut input, BitOrder::Msb);
+        assert_eq!(reader.read_u32(24).unwrap(), 0x425A68);
+        assert_eq!(reader.read_u32(8).unwrap(), u32::from(b'9'));
+        let magic =
+            u64::from(reader.read_u32(24).unwrap()) << 24 | u64::from(reader.read_u32(24).unwrap());
+        assert_eq!(magic, 0x1772_4538_5090);
+        assert_eq!(reader.read_u32(32).unwrap(), 0);
+
+        let mut writer = BitWriter::with_order(BitOrder::Msb);
+        writer.write_u32(0x425A68, 24).unwrap();
+        writer.write_bits(u16::from(b'9'), 8).unwrap();
+        writer.write_u32(0x177245, 24).unwrap();
+        writer.write_u32(0x385090, 24).unwrap();
+        writer.write_u32(0, 32).unwrap();
+        assert_eq!(writer.finish().unwrap(), empty);
+    }
+
+    #[test]
+    fn suspend_and_resume() {
+        for (order, capacity) in [
+            (BitOrder::Lsb, 5),
+            (BitOrder::Msb, 5),
+            (BitOrder::Lsb, 8192),
+        ] {
+            let mut writer = BitWriter::with_order(order);
+            for i in 0..1000u16 {
+                writer.write_bits(i % 512, 9).unwrap();
+            }
+            let vec = writer.finish().unwrap();
+            let mut input = BufReader::with_capacity(capacity, Cursor::new(vec));
+            let mut state = BitState::default();
+            let lsb = order == BitOrder::Lsb;
+            for chunk in 0..100u16 {
+                let mut reader = BitReader::resume(&mut input, order, state);
+                for i in 0..10 {
+                    let v = reader.read_bits(9, lsb).unwrap();
+                    assert_eq!(v, (chunk * 10 + i) % 512);
+                }
+                state = reader.into_state();
+                assert_eq!(state.position, u64::from(chunk + 1) * 90);
+            }
+        }
+    }
 }
@@ -9,3 +9,9 @@ pub const MIN_LEN: usize = 3;
 pub const NUM_DIST_CODE: u16 = 30;
 pub const MIN_NUM_LIT: usize = 257;
 pub const MAX_NUM_LIT: usize = 288;
+pub const MAX_CLEN_BITS: usize = 7;
+pub const RSYNC_WINDOW: usize = 8192;
+pub const LENGTH_BASE: [usize; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
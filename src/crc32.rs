@@ -0,0 +1,184 @@
+/// Reversed CRC-32 polynomial used by zip, gzip and PNG
+const POLY: u32 = 0xEDB8_8320;
+const GF2_DIM: usize = 32;
+
+lazy_static! {
+    static ref TABLES: [[u32; 256]; 8] = make_tables();
+}
+
+/// Tables for slice-by-8: entry `k` advances a byte through `k` more zero bytes
+fn make_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0u32; 256]; 8];
+    for (i, entry) in tables[0].iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    for k in 1..8 {
+        for i in 0..256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+        }
+    }
+    tables
+}
+
+/// Running CRC-32 checksum
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0 }
+    }
+
+    /// Carry on from a checksum computed earlier
+    pub fn from_value(crc: u32) -> Crc32 {
+        Crc32 { crc }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        let t = &*TABLES;
+        let mut crc = !self.crc;
+        let mut words = bytes.chunks_exact(8);
+        for w in &mut words {
+            let one = u32::from_le_bytes([w[0], w[1], w[2], w[3]]) ^ crc;
+            let two = u32::from_le_bytes([w[4], w[5], w[6], w[7]]);
+            crc = t[7][(one & 0xFF) as usize]
+                ^ t[6][((one >> 8) & 0xFF) as usize]
+                ^ t[5][((one >> 16) & 0xFF) as usize]
+                ^ t[4][(one >> 24) as usize]
+                ^ t[3][(two & 0xFF) as usize]
+                ^ t[2][((two >> 8) & 0xFF) as usize]
+                ^ t[1][((two >> 16) & 0xFF) as usize]
+                ^ t[0][(two >> 24) as usize];
+        }
+        for b in words.remainder() {
+            crc = (crc >> 8) ^ t[0][((crc ^ u32::from(*b)) & 0xFF) as usize];
+        }
+        self.crc = !crc;
+    }
+
+    pub fn value(&self) -> u32 {
+        self.crc
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.value()
+}
+
+fn gf2_matrix_times(mat: &[u32], mut vec: u32) -> u32 {
+    let mut sum = 0;
+    let mut i = 0;
+    while vec != 0 {
+        if vec & 1 == 1 {
+            sum ^= mat[i];
+        }
+        vec >>= 1;
+        i += 1;
+    }
+    sum
+}
+
+fn gf2_matrix_square(square: &mut [u32], mat: &[u32]) {
+    for n in 0..GF2_DIM {
+        square[n] = gf2_matrix_times(mat, mat[n]);
+    }
+}
+
+/// CRC-32 of two concatenated pieces from the CRC-32 of each and the length of the second
+pub fn crc32_combine(crc1: u32, crc2: u32, len2: u64) -> u32 {
+    if len2 == 0 {
+        return crc1;
+    }
+    let mut even = [0u32; GF2_DIM];
+    let mut odd = [0u32; GF2_DIM];
+    // Operator for one zero bit
+    odd[0] = POLY;
+    let mut row = 1;
+    for o in odd.iter_mut().skip(1) {
+        *o = row;
+        row <<= 1;
+    }
+    // Two zero bits, then four
+    gf2_matrix_square(&mut even, &odd);
+    gf2_matrix_square(&mut odd, &even);
+    let mut crc1 = crc1;
+    let mut len2 = len2;
+    loop {
+        gf2_matrix_square(&mut even, &odd);
+        if len2 & 1 == 1 {
+            crc1 = gf2_matrix_times(&even, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+        gf2_matrix_square(&mut odd, &even);
+        if len2 & 1 == 1 {
+            crc1 = gf2_matrix_times(&odd, crc1);
+        }
+        len2 >>= 1;
+        if len2 == 0 {
+            break;
+        }
+    }
+    crc1 ^ crc2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::{self, RngCore};
+
+    fn bitwise(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for b in bytes {
+            crc ^= u32::from(*b);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ POLY
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn incremental_and_combine() {
+        let mut v = vec![0u8; 10_007];
+        rand::thread_rng().fill_bytes(&mut v);
+        let whole = crc32(&v);
+        assert_eq!(whole, bitwise(&v));
+        let (a, b) = v.split_at(3_333);
+        let mut crc = Crc32::from_value(crc32(a));
+        crc.update(b);
+        assert_eq!(crc.value(), whole);
+        assert_eq!(crc32_combine(crc32(a), crc32(b), b.len() as u64), whole);
+        assert_eq!(crc32_combine(whole, 0, 0), whole);
+    }
+}
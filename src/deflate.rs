@@ -1,14 +1,13 @@
-use std::collections::HashMap;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::iter::FromIterator;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write};
 use std::u16;
 
-use crc::crc32::{Digest, Hasher32, IEEE};
 use num::FromPrimitive;
 
 use crate::bitstream::*;
 use crate::constant::*;
+use crate::crc32::Crc32;
 use crate::huffman::*;
+use crate::lz77::*;
 use crate::util::*;
 
 #[repr(u16)]
@@ -30,11 +29,219 @@ pub enum LZ77 {
     Copy { len: usize, dist: usize },
 }
 
+/// Data structure used to look up earlier occurrences of a string
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Finder {
+    /// Walk a chain of positions sharing the same hash
+    HashChain,
+    /// Keep positions sharing the same hash in a binary search tree
+    BinaryTree,
+}
+
+/// Compression settings
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    pub finder: Finder,
+    /// Maximum number of earlier positions to compare against, 0 to store the input as is
+    pub max_chain: usize,
+    /// Stop searching once a match is at least this long
+    pub nice_len: usize,
+    /// Sync flush wherever the input content says so, like gzip --rsyncable
+    pub rsyncable: bool,
+    /// Attach a `BlockReport` to every block
+    pub report: bool,
+}
+
+impl Options {
+    /// Settings for compression levels 0 (store only) and 1 (fastest) to 9 (best), as in zlib
+    pub fn level(level: u8) -> Options {
+        let (finder, max_chain, nice_len) = match level {
+            0 => (Finder::HashChain, 0, 0),
+            1 => (Finder::HashChain, 4, 8),
+            2 => (Finder::HashChain, 8, 16),
+            3 => (Finder::HashChain, 16, 32),
+            4 => (Finder::HashChain, 24, 32),
+            5 => (Finder::HashChain, 32, 64),
+            6 => (Finder::HashChain, 128, 128),
+            7 => (Finder::HashChain, 256, 128),
+            8 => (Finder::BinaryTree, 64, MAX_LEN),
+            _ => (Finder::BinaryTree, 256, MAX_LEN),
+        };
+        Options {
+            finder,
+            max_chain,
+            nice_len,
+            rsyncable: false,
+            report: false,
+        }
+    }
+}
+
+/// Rolling sum over the last `RSYNC_WINDOW` bytes of input
+///
+/// A block ends wherever the sum is a multiple of the window size, so block
+/// boundaries depend only on nearby content and come back in step after an edit.
+/// The sum tends to hit a multiple several times in a row, so only a hit with no
+/// other hit in the window before it counts, which again depends only on content.
+struct Rsync {
+    sum: u32,
+    history: Vec<u8>,
+    pos: usize,
+    // Bytes since the sum last hit a multiple of the window size
+    since: usize,
+}
+
+impl Rsync {
+    fn new() -> Rsync {
+        Rsync {
+            sum: 0,
+            history: Vec::with_capacity(RSYNC_WINDOW),
+            pos: 0,
+            since: 0,
+        }
+    }
+
+    /// Offsets in `data` right after which a block should end
+    fn boundaries(&mut self, data: &[u8]) -> Vec<usize> {
+        let mut v = Vec::new();
+        for (i, b) in data.iter().enumerate() {
+            self.sum += u32::from(*b);
+            self.since += 1;
+            if self.history.len() < RSYNC_WINDOW {
+                self.history.push(*b);
+                continue;
+            }
+            self.sum -= u32::from(self.history[self.pos]);
+            self.history[self.pos] = *b;
+            self.pos = (self.pos + 1) % RSYNC_WINDOW;
+            if self.sum & (RSYNC_WINDOW as u32 - 1) == 0 {
+                if self.since >= RSYNC_WINDOW {
+                    v.push(i + 1);
+                }
+                self.since = 0;
+            }
+        }
+        v
+    }
+}
+
+/// Where blocks end as input comes in: at rsync boundaries when asked for, and at
+/// most `max_block` bytes after the end before
+///
+/// Ends never fall where a read happens to stop, so an edit does not move the
+/// blocks after it.
+pub(crate) struct BlockEnds {
+    rsync: Option<Rsync>,
+    max_block: usize,
+    // Input since the last block end
+    pending: usize,
+}
+
+impl BlockEnds {
+    pub(crate) fn new(rsyncable: bool, max_block: usize) -> BlockEnds {
+        BlockEnds {
+            rsync: if rsyncable { Some(Rsync::new()) } else { None },
+            max_block,
+            pending: 0,
+        }
+    }
+
+    /// Block ends up to the end of `data`, counted from the last block end before
+    /// it, each with whether it is an rsync boundary
+    ///
+    /// If `last` is set, the last block ends with `data`.
+    pub(crate) fn next(&mut self, data: &[u8], last: bool) -> Vec<(usize, bool)> {
+        let flushes = match self.rsync {
+            Some(ref mut r) => r.boundaries(data),
+            None => Vec::new(),
+        };
+        let offset = self.pending;
+        let total = offset + data.len();
+        let mut ends = Vec::new();
+        let mut start = 0;
+        for end in flushes.into_iter().map(|b| b + offset) {
+            while end - start > self.max_block {
+                start += self.max_block;
+                ends.push((start, false));
+            }
+            ends.push((end, true));
+            start = end;
+        }
+        while total - start >= self.max_block {
+            start += self.max_block;
+            ends.push((start, false));
+        }
+        if last && ends.last().map(|e| e.0) != Some(total) {
+            ends.push((total, false));
+            start = total;
+        }
+        self.pending = total - start;
+        ends
+    }
+}
+
+/// Detailed account of how one block was coded
+#[derive(Clone, Debug, Default)]
+pub struct BlockReport {
+    pub literals: usize,
+    pub matches: usize,
+    /// Number of matches of each length, indexed by length
+    pub length_histogram: Vec<usize>,
+    /// Number of matches of each distance code
+    pub distance_histogram: Vec<usize>,
+    /// Bits for the block header, the code tables, the end-of-block code and any
+    /// flush written after the block
+    pub header_bits: u64,
+    pub literal_bits: u64,
+    /// Bits for length and distance codes, extra bits included
+    pub match_bits: u64,
+    pub lit_code_lengths: Vec<u8>,
+    pub dist_code_lengths: Vec<u8>,
+}
+
+/// What became of one block of input
+#[derive(Clone, Debug, Default)]
+pub struct BlockInfo {
+    pub uncompressed_size: usize,
+    pub compressed_bits: u64,
+    pub last: bool,
+    /// Only there if `Options::report` was set
+    pub report: Option<BlockReport>,
+}
+
+impl BlockInfo {
+    /// Count `bits` of flushing written after the block
+    pub(crate) fn add_flush_bits(&mut self, bits: u64) {
+        self.compressed_bits += bits;
+        if let Some(ref mut r) = self.report {
+            r.header_bits += bits;
+        }
+    }
+}
+
+/// What became of a whole stream
+#[derive(Clone, Debug, Default)]
+pub struct DeflateStats {
+    pub uncompressed_size: u64,
+    /// CRC-32 of the uncompressed input, as stored in gzip and zip headers
+    pub crc32: u32,
+    pub compressed_size: u64,
+    pub blocks: Vec<BlockInfo>,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::level(6)
+    }
+}
+
 //static fixed_lit_count: Vec<u16> = vec!(0,0,0,0,0,0,280-256,144+288-280,256-244);
 
-fn read_length<R: Read>(lit: u16, reader: &mut BitReader<R>) -> Result<u16, Error> {
+fn read_length<R: BufRead>(lit: u16, reader: &mut BitReader<R>) -> Result<u16, Error> {
     let mut len = lit - (END_OF_BLOCK + 1);
-    if len < 8 {
+    if lit == 285 {
+        len = MAX_LEN as u16;
+    } else if len < 8 {
         len += 3;
     } else {
         let extra_bits = (len - 4) / 4;
@@ -51,13 +258,13 @@ fn read_length<R: Read>(lit: u16, reader: &mut BitReader<R>) -> Result<u16, Erro
 fn length_code(len: usize) -> Result<(usize, u8), Error> {
     //let bits = ((len - 10) as f32).log2().ceil() - 2;
     match len {
-        3...10 => Ok((len + 254, 0)),
-        11...18 => Ok((260 + ((len + 1) >> 1), 1)),
-        19...34 => Ok((264 + ((len + 1) >> 2), 2)),
-        35...66 => Ok((269 + ((len - 3) >> 3), 3)),
-        67...130 => Ok((273 + ((len - 3) >> 4), 4)),
-        131...257 => Ok((277 + ((len - 3) >> 5), 5)),
-        258 => Ok((285, 6)),
+        3..=10 => Ok((len + 254, 0)),
+        11..=18 => Ok((259 + ((len + 1) >> 1), 1)),
+        19..=34 => Ok((264 + ((len + 1) >> 2), 2)),
+        35..=66 => Ok((269 + ((len - 3) >> 3), 3)),
+        67..=130 => Ok((273 + ((len - 3) >> 4), 4)),
+        131..=257 => Ok((277 + ((len - 3) >> 5), 5)),
+        258 => Ok((285, 0)),
         _ => Err(Error::new(ErrorKind::Other, "Incorrect length")),
     }
 }
@@ -65,8 +272,8 @@ fn length_code(len: usize) -> Result<(usize, u8), Error> {
 fn dist_code(dist: usize) -> Result<(usize, u8), Error> {
     let dm1 = dist - 1;
     match dm1 {
-        0...3 => Ok((dm1, 0u8)),
-        4...32_767 => {
+        0..=3 => Ok((dm1, 0u8)),
+        4..=32_767 => {
             let bits = (dm1 as f32).log2().floor() as usize - 1;
             let rem = (dm1 - (1 << (bits + 1))) >> bits;
             Ok((bits * 2 + 2 + rem, bits as u8))
@@ -75,7 +282,7 @@ fn dist_code(dist: usize) -> Result<(usize, u8), Error> {
     }
 }
 
-fn read_distance<R: Read>(dcode: u16, reader: &mut BitReader<R>) -> Result<u16, Error> {
+fn read_distance<R: BufRead>(dcode: u16, reader: &mut BitReader<R>) -> Result<u16, Error> {
     let distance = if dcode > 3 {
         let extra_bits = (dcode - 2) / 2;
         let extra = reader.read_bits(extra_bits as u8, true)?;
@@ -87,7 +294,7 @@ fn read_distance<R: Read>(dcode: u16, reader: &mut BitReader<R>) -> Result<u16,
     Ok(distance + 1)
 }
 
-fn read_code_lengths<R: Read>(
+fn read_code_lengths<R: BufRead>(
     reader: &mut BitReader<R>,
     clen_dec: &HuffmanDec,
     n: usize,
@@ -101,12 +308,14 @@ fn read_code_lengths<R: Read>(
         let mut len: u8 = 0;
         debug!("code len {}", s);
         match s {
-            0...15 => {
+            0..=15 => {
                 lens[index] = s;
                 index += 1;
             }
             16 => {
-                debug_assert!(!lens.is_empty());
+                if index == 0 {
+                    return Err(CodeLengthError::NothingToRepeat.into());
+                }
                 len = lens[index - 1];
                 count = reader.read_bits(2, true)? + 3;
             }
@@ -121,7 +330,9 @@ fn read_code_lengths<R: Read>(
             }
         }
         if s > 15 && s < 19 {
-            assert!(index + count as usize <= n);
+            if index + count as usize > n {
+                return Err(CodeLengthError::TooManyLengths.into());
+            }
             for l in lens.iter_mut().skip(index).take(count as usize) {
                 *l = len;
             }
@@ -132,25 +343,36 @@ fn read_code_lengths<R: Read>(
     Ok(lens)
 }
 
-fn read_code_table<R: Read>(reader: &mut BitReader<R>) -> Result<(HuffmanDec, HuffmanDec), Error> {
+fn read_code_table<R: BufRead>(
+    reader: &mut BitReader<R>,
+) -> Result<(HuffmanDec, HuffmanDec), Error> {
     let hlit = reader.read_bits(5, true)? as usize + 257;
     let hdist = reader.read_bits(5, true)? as usize + 1;
     let hclen = reader.read_bits(4, true)? as usize + 4;
     let max_hclen = HCLEN_ORDER.len();
     let mut hclen_len = vec![0 as u8; max_hclen];
-    assert!(hlit <= 286 && hclen <= max_hclen && hdist <= 32);
+    if hlit > 286 || hdist > 30 {
+        return Err(CodeLengthError::TooManyCodes.into());
+    }
     for i in HCLEN_ORDER.iter().take(hclen) {
         hclen_len[*i] = reader.read_bits(3, true)? as u8;
     }
-    let clen_dec = gen_huffman_dec(&hclen_len, max_hclen as u16);
-    let hlit_len = read_code_lengths(reader, &clen_dec, hlit)?;
-    let hdist_len = read_code_lengths(reader, &clen_dec, hdist)?;
+    // Only the distance code may be incomplete
+    check_lengths(&hclen_len, false)?;
+    let clen_dec = gen_huffman_dec(&hclen_len, max_hclen as u16)?;
+    // Both codes' lengths form one sequence, and a repeat may run from one into the other
+    let mut hlit_len = read_code_lengths(reader, &clen_dec, hlit + hdist)?;
+    let hdist_len = hlit_len.split_off(hlit);
     debug!("Read code table done");
     info!("hlit_len: {} {:?}", hlit, hlit_len);
     info!("hdist_len: {} {:?}", hdist, hdist_len);
+    if hlit_len[END_OF_BLOCK as usize] == 0 {
+        return Err(CodeLengthError::NoEndOfBlock.into());
+    }
+    check_lengths(&hlit_len, false)?;
     Ok((
-        gen_huffman_dec(&hlit_len, hlit as u16),
-        gen_huffman_dec(&hdist_len, hdist as u16),
+        gen_huffman_dec(&hlit_len, hlit as u16)?,
+        gen_huffman_dec(&hdist_len, hdist as u16)?,
     ))
 }
 
@@ -179,7 +401,7 @@ fn encode_code_lengths(clen: &[u8]) -> Vec<CodeLength> {
                     v.push(CodeLength::Single(prev));
                 }
             }
-            1...2 => {
+            1..=2 => {
                 if prev != 0 {
                     repeat += 1;
                 }
@@ -187,7 +409,7 @@ fn encode_code_lengths(clen: &[u8]) -> Vec<CodeLength> {
                     v.push(CodeLength::Single(prev));
                 }
             }
-            3...10 => {
+            3..=10 => {
                 if prev != 0 {
                     v.push(CodeLength::Single(prev));
                 }
@@ -196,7 +418,7 @@ fn encode_code_lengths(clen: &[u8]) -> Vec<CodeLength> {
                     repeat: repeat - 3,
                 });
             }
-            11...138 => v.push(CodeLength::Repeat {
+            11..=138 => v.push(CodeLength::Repeat {
                 code: 18,
                 repeat: repeat - 11,
             }),
@@ -234,61 +456,64 @@ fn reordered_code_lengths(clens: &[u8]) -> Vec<u8> {
     mapped_clens
 }
 
-fn write_code_table(writer: &mut BitWriter, lit_clens: &[u8], dist_clens: &[u8]) -> Vec<u8> {
+fn write_code_table<W: Write>(
+    writer: &mut BitWriter<W>,
+    lit_clens: &[u8],
+    dist_clens: &[u8],
+) -> Result<(), Error> {
     let hlit = lit_clens.len() - 257;
-    let mut v = writer.write_bits(hlit as u16, 5);
+    writer.write_bits(hlit as u16, 5)?;
     let hdist = dist_clens.len() - 1;
-    v.extend(writer.write_bits(hdist as u16, 5).iter());
+    writer.write_bits(hdist as u16, 5)?;
     let lit_eclens = encode_code_lengths(lit_clens);
     let dist_eclens = encode_code_lengths(dist_clens);
     let mut freq = vec![0 as usize; HCLEN_ORDER.len()];
     update_freq(&mut freq, &lit_eclens);
     update_freq(&mut freq, &dist_eclens);
-    let clen = assign_lengths(&freq);
+    ensure_two_codes(&mut freq);
+    let clen = assign_limited_lengths(&freq, MAX_CLEN_BITS);
     let mapped_clens = reordered_code_lengths(&clen);
     let hclen = mapped_clens.len();
-    v.extend(writer.write_bits((hclen - 4) as u16, 4).iter());
+    writer.write_bits((hclen - 4) as u16, 4)?;
     debug!("Write clen codes");
     for i in mapped_clens {
-        v.extend(writer.write_bits(u16::from(i), 3).iter());
+        writer.write_bits(u16::from(i), 3)?;
         //debug!("{}->{}", HCLEN_ORDER[i], mapped_clens[i]);
     }
     let enc = gen_huffman_enc(&clen);
     debug!("Write lit code lengths");
-    v.extend(write_code_lengths(writer, &lit_eclens, &enc).iter());
-    v.extend(write_code_lengths(writer, &dist_eclens, &enc).iter());
-    v
+    write_code_lengths(writer, &lit_eclens, &enc)?;
+    write_code_lengths(writer, &dist_eclens, &enc)
 }
 
-fn write_code_lengths(
-    writer: &mut BitWriter,
+fn write_code_lengths<W: Write>(
+    writer: &mut BitWriter<W>,
     eclens: &[CodeLength],
     enc: &[(Bits, u8)],
-) -> Vec<u8> {
-    let mut v = Vec::new();
+) -> Result<(), Error> {
     for cl in eclens {
         match *cl {
             CodeLength::Single(c) => {
                 let (bits, bit_len) = enc[c as usize];
-                v.extend(writer.write_bits(bits, bit_len).iter());
+                writer.write_bits(bits, bit_len)?;
             }
             CodeLength::Repeat { code: l, repeat: r } => {
                 let (bits, bit_len) = enc[l as usize];
-                v.extend(writer.write_bits(bits, bit_len).iter());
+                writer.write_bits(bits, bit_len)?;
                 match l {
-                    16 | 17 => v.extend(writer.write_bits(u16::from(r), l - 14)),
-                    18 => v.extend(writer.write_bits(u16::from(r), 7)),
+                    16 | 17 => writer.write_bits(u16::from(r), l - 14)?,
+                    18 => writer.write_bits(u16::from(r), 7)?,
                     _ => panic!("Illegal code length Huffman code"),
                 }
             }
         };
     }
-    v
+    Ok(())
 }
 
 // Not being used
 #[allow(dead_code)]
-fn read_fixed_literal<R: Read>(reader: &mut BitReader<R>) -> u16 {
+fn read_fixed_literal<R: BufRead>(reader: &mut BitReader<R>) -> u16 {
     let mut lit = reader.read_bits(7, false).unwrap();
     if lit <= 0b001_0111 {
         lit += 256;
@@ -312,248 +537,543 @@ fn read_fixed_literal<R: Read>(reader: &mut BitReader<R>) -> u16 {
     lit
 }
 
-#[allow(clippy::cyclomatic_complexity)]
+/// Write out all but the last `keep` bytes of the window, leaving out the first
+/// `primed` bytes, which hold a preset dictionary rather than output
+fn drain_window<W: Write>(
+    window: &mut Vec<u8>,
+    keep: usize,
+    primed: &mut usize,
+    output: &mut W,
+    hasher: &mut Crc32,
+) -> Result<(), Error> {
+    if window.len() > keep {
+        let to_write = window.len() - keep;
+        if to_write > *primed {
+            output.write_all(&window[*primed..to_write])?;
+            hasher.update(&window[*primed..to_write]);
+        }
+        *primed = primed.saturating_sub(to_write);
+        window.drain(0..to_write);
+    }
+    Ok(())
+}
+
+fn inflate_stored<R: BufRead>(
+    reader: &mut BitReader<R>,
+    window: &mut Vec<u8>,
+) -> Result<usize, Error> {
+    reader.align_to_byte();
+    let len = reader.read_bits(16, true)?;
+    let nlen = reader.read_bits(16, true)?;
+    if len != !nlen {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("Bad stored block length at bit {}", reader.bit_position()),
+        ));
+    }
+    debug!("Stored block of {} bytes", len);
+    let start = window.len();
+    window.resize(start + len as usize, 0);
+    reader.read_bytes(&mut window[start..])?;
+    Ok(len as usize)
+}
+
 pub fn inflate<R: Read, W: Write>(
     input: &mut BufReader<R>,
     output: &mut BufWriter<W>,
 ) -> Result<(u32, u32), Error> {
-    let mut decompressed_size: u32 = 0;
-    let mut reader = BitReader::new(input);
-    let last_block_bit = reader.read_bits(1, true)?;
-    if last_block_bit == 1 {
-        debug!("Last Block");
-    } else {
-        debug!("Not last block");
-    }
-    let block_type = BlockType::from_u8(reader.read_bits(2, true)? as u8);
-    let mut hasher = Digest::new(IEEE);
-    let mut dec = (HuffmanDec::new(), HuffmanDec::new());
-    match block_type {
-        Some(BlockType::Store) => debug!("Store"),
-        Some(BlockType::FixedHuffman) => debug!("Fixed Huffman codes"),
-        Some(BlockType::DynamicHuffman) => {
-            debug!("Dynamic Huffman codes");
-            dec = read_code_table(&mut reader)?;
-        }
-        _ => return Err(Error::new(ErrorKind::Other, "Bad block type")),
-    }
-    info!("Dec {:?}", dec);
-    let block_type = block_type.unwrap();
-    let mut window = Vec::<u8>::with_capacity(MAX_DIST + MAX_LEN);
-    loop {
-        let lit = match block_type {
-            BlockType::Store => reader.read_bits(8, false)? as u16,
-            BlockType::FixedHuffman => read_code(&mut reader, &FIXED_LITERAL_DEC)?,
-            BlockType::DynamicHuffman => read_code(&mut reader, &dec.0)?,
-        };
-        match lit {
-            0...255 => {
-                let byte = lit as u8;
-                debug!("byte {}", byte);
-                if window.len() == MAX_DIST {
-                    let mut b: [u8; 1] = [0; 1];
-                    b[0] = window.remove(0); //workaround clippy bug
-                    debug!("write");
-                    let _ = output.write(&b)?;
-                    debug!("hasher");
-                    hasher.write(&b);
+    inflate_with_dict(input, output, &[])
+}
+
+/// Decompress a stream that may refer back into the preset dictionary `dict`
+pub fn inflate_with_dict<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    dict: &[u8],
+) -> Result<(u32, u32), Error> {
+    let mut inflater = Inflater::new(dict);
+    while !inflater.step(input, output)? {}
+    Ok(inflater.size_and_crc())
+}
+
+/// Inflate that can stop whenever it has output ready and carry on later
+pub struct Inflater {
+    window: Vec<u8>,
+    primed: usize,
+    decompressed_size: u32,
+    hasher: Crc32,
+    bits: BitState,
+    // Huffman block being decoded, with its literal and distance codes
+    block: Option<(BlockType, HuffmanDec, HuffmanDec)>,
+    last: bool,
+    done: bool,
+}
+
+impl Inflater {
+    /// Get ready for a stream that may refer back into the preset dictionary `dict`
+    pub fn new(dict: &[u8]) -> Inflater {
+        let mut window = Vec::<u8>::with_capacity(2 * MAX_DIST + u16::MAX as usize);
+        window.extend_from_slice(&dict[dict.len() - dict.len().min(MAX_DIST)..]);
+        Inflater {
+            primed: window.len(),
+            window,
+            decompressed_size: 0,
+            hasher: Crc32::new(),
+            bits: BitState::default(),
+            block: None,
+            last: false,
+            done: false,
+        }
+    }
+
+    /// Size modulo 2^32 and CRC-32 of the output so far
+    pub fn size_and_crc(&self) -> (u32, u32) {
+        (self.decompressed_size, self.hasher.value())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// Decode until some output has been written to `output`, returning true once the
+    /// stream has ended
+    ///
+    /// Nothing after the end of the stream is taken from `input`.
+    pub fn step<R: BufRead, W: Write>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+    ) -> Result<bool, Error> {
+        if self.done {
+            return Ok(true);
+        }
+        let mut reader = BitReader::resume(input, BitOrder::Lsb, self.bits);
+        let result = self.decode(&mut reader, output);
+        self.bits = reader.into_state();
+        result
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn decode<R: BufRead, W: Write>(
+        &mut self,
+        reader: &mut BitReader<R>,
+        output: &mut W,
+    ) -> Result<bool, Error> {
+        let window = &mut self.window;
+        loop {
+            let (block_type, lit_dec, dist_dec) = match self.block.take() {
+                Some(block) => block,
+                None if self.last => {
+                    drain_window(window, 0, &mut self.primed, output, &mut self.hasher)?;
+                    self.done = true;
+                    return Ok(true);
                 }
-                window.push(byte);
-                info!("inflate lit {:02x} at {}", lit, decompressed_size);
-                decompressed_size += 1;
-            }
-            END_OF_BLOCK => {
-                debug!("end of block");
-                break;
-            }
-            257...285 => {
-                let len = read_length(lit, &mut reader)? as usize;
-                assert!(len <= MAX_LEN);
+                None => {
+                    self.last = reader.read_bits(1, true)? == 1;
+                    if self.last {
+                        debug!("Last Block");
+                    } else {
+                        debug!("Not last block");
+                    }
+                    match BlockType::from_u8(reader.read_bits(2, true)? as u8) {
+                        Some(BlockType::Store) => {
+                            debug!("Store");
+                            self.decompressed_size = self
+                                .decompressed_size
+                                .wrapping_add(inflate_stored(reader, window)? as u32);
+                            drain_window(
+                                window,
+                                MAX_DIST,
+                                &mut self.primed,
+                                output,
+                                &mut self.hasher,
+                            )?;
+                            return Ok(false);
+                        }
+                        Some(BlockType::FixedHuffman) => {
+                            debug!("Fixed Huffman codes");
+                            (
+                                BlockType::FixedHuffman,
+                                HuffmanDec::new(),
+                                HuffmanDec::new(),
+                            )
+                        }
+                        Some(BlockType::DynamicHuffman) => {
+                            debug!("Dynamic Huffman codes");
+                            let dec = read_code_table(reader)?;
+                            info!("Dec {:?}", dec);
+                            (BlockType::DynamicHuffman, dec.0, dec.1)
+                        }
+                        _ => {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("Bad block type at bit {}", reader.bit_position()),
+                            ))
+                        }
+                    }
+                }
+            };
+            loop {
+                let lit = match block_type {
+                    BlockType::FixedHuffman => read_code(reader, &FIXED_LITERAL_DEC)?,
+                    _ => read_code(reader, &lit_dec)?,
+                };
+                match lit {
+                    0..=255 => {
+                        let byte = lit as u8;
+                        debug!("byte {}", byte);
+                        window.push(byte);
+                        info!("inflate lit {:02x} at {}", lit, self.decompressed_size);
+                        self.decompressed_size = self.decompressed_size.wrapping_add(1);
+                    }
+                    END_OF_BLOCK => {
+                        debug!("end of block");
+                        break;
+                    }
+                    257..=285 => {
+                        let len = read_length(lit, reader)? as usize;
+                        assert!(len <= MAX_LEN);
 
-                let dcode = match block_type {
-                    BlockType::FixedHuffman => reader.read_bits(5, false)?,
-                    BlockType::DynamicHuffman => read_code(&mut reader, &dec.1)?,
+                        let dcode = match block_type {
+                            BlockType::FixedHuffman => reader.read_bits(5, false)?,
+                            _ => read_code(reader, &dist_dec)?,
+                        };
+                        if dcode >= NUM_DIST_CODE {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("Bad distance code at bit {}", reader.bit_position()),
+                            ));
+                        }
+                        debug!("dcode {}", dcode);
+                        let dist = read_distance(dcode, reader)? as usize;
+                        info!("inflate copy {} {}", dist, len);
+                        if dist > window.len() {
+                            return Err(Error::new(
+                                ErrorKind::Other,
+                                format!("Distance too far back at bit {}", reader.bit_position()),
+                            ));
+                        }
+                        // Byte by byte, as the copy overlaps itself when len > dist
+                        let first = window.len() - dist;
+                        for i in 0..len {
+                            let byte = window[first + i];
+                            window.push(byte);
+                        }
+                        self.decompressed_size = self.decompressed_size.wrapping_add(len as u32);
+                        debug!("decompressed size: {}", self.decompressed_size);
+                    }
                     _ => {
                         return Err(Error::new(
                             ErrorKind::Other,
-                            "Bad block type; Shouldn't reach here",
-                        ))
+                            format!("Bad literal at bit {}", reader.bit_position()),
+                        ));
                     }
-                };
-                assert!(dcode < NUM_DIST_CODE);
-                debug!("dcode {}", dcode);
-                let dist = read_distance(dcode, &mut reader)? as usize;
-                debug!("{}: {}", decompressed_size, to_hex_string(&window));
-                info!("inflate copy {} {}", dist, len);
-                assert!(dist > 0 && dist < MAX_DIST);
-                assert!(dist <= window.len());
-                if window.len() + len > window.capacity() {
-                    let to_write = window.len() + len - window.capacity();
-                    let _ = output.write(&window[0..to_write])?;
-                    hasher.write(&window[0..to_write]);
-                    window.drain(0..to_write);
-                }
-                //Fix the case len > dist
-                let mut cur_len = if len > dist { dist } else { len };
-                let mut copied = 0;
-                let first = window.len() - dist;
-                let seg = Vec::from_iter(window[first..first + cur_len].iter().cloned());
-                debug!("copy: {}", to_hex_string(&seg));
-                while copied + cur_len <= len {
-                    window.extend_from_slice(&seg);
-                    copied += cur_len;
                 }
-                if copied < len {
-                    cur_len = len - copied;
-                    window.extend_from_slice(&seg[0..cur_len]);
+                if window.len() >= 2 * MAX_DIST {
+                    drain_window(window, MAX_DIST, &mut self.primed, output, &mut self.hasher)?;
+                    self.block = Some((block_type, lit_dec, dist_dec));
+                    return Ok(false);
                 }
-                decompressed_size += len as u32;
-                debug!("decompressed size: {}", decompressed_size);
-            }
-            _ => {
-                return Err(Error::new(ErrorKind::Other, "Bad literal"));
             }
         }
     }
-    let _ = output.write(window.as_slice())?;
-    hasher.write(window.as_slice());
-    Ok((decompressed_size, hasher.sum32()))
 }
 
-fn compare(bytes: &[u8], i: usize, j: usize) -> usize {
-    let mut len = 0;
-    while j + len < bytes.len() && bytes[i + len] == bytes[j + len] {
-        len += 1;
+/// Read up to `size` bytes, stopping short only at the end of the input
+pub fn read_chunk<R: Read>(input: &mut R, size: usize) -> Result<Vec<u8>, Error> {
+    let mut chunk = Vec::with_capacity(size);
+    input.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Make sure at least two codes get a length, as some decoders reject incomplete codes
+fn ensure_two_codes(freq: &mut Vec<usize>) {
+    let mut i = 0;
+    while freq.iter().filter(|f| **f > 0).count() < 2 {
+        if i == freq.len() {
+            freq.push(0);
+        }
+        if freq[i] == 0 {
+            freq[i] = 1;
+        }
+        i += 1;
     }
-    len
 }
 
-fn max_match(bytes: &[u8], prev: &[usize], len: usize, i: usize) -> (usize, usize) {
-    let mut next = prev[i];
-    let mut max_len: usize = 0;
-    let mut max_dist: usize = 0;
-    while next != len && i - next < MAX_DIST {
-        let len = compare(&bytes, i, next);
-        if len > max_len {
-            max_dist = i - next;
-            max_len = len;
+fn block_report(
+    vlz: &[LZ77],
+    lenc: &[(Bits, u8)],
+    denc: &[(Bits, u8)],
+    lit_clens: &[u8],
+    dist_clens: &[u8],
+) -> BlockReport {
+    let mut report = BlockReport {
+        length_histogram: vec![0; MAX_LEN + 1],
+        distance_histogram: vec![0; NUM_DIST_CODE as usize],
+        lit_code_lengths: lit_clens.to_vec(),
+        dist_code_lengths: dist_clens.to_vec(),
+        ..BlockReport::default()
+    };
+    for lz in vlz {
+        match *lz {
+            LZ77::Literal(l) => {
+                report.literals += 1;
+                report.literal_bits += u64::from(lenc[l as usize].1);
+            }
+            LZ77::Copy { len, dist } => {
+                let lc = length_code(len).unwrap();
+                let dc = dist_code(dist).unwrap();
+                report.matches += 1;
+                report.length_histogram[len] += 1;
+                report.distance_histogram[dc.0] += 1;
+                report.match_bits += u64::from(lenc[lc.0].1 + lc.1 + denc[dc.0].1 + dc.1);
+            }
         }
-        next = prev[next];
     }
-    (max_dist, max_len)
+    report
 }
 
-#[allow(clippy::cyclomatic_complexity)]
-pub fn deflate<R: Read, W: Write>(
-    input: &mut BufReader<R>,
-    output: &mut BufWriter<W>,
-) -> Result<(u32, u32), Error> {
-    let mut window = Vec::<u8>::new();
-    let mut bytes = [0 as u8; u16::MAX as usize];
-    let mut vlz = Vec::<LZ77>::new();
-    let mut hasher = Digest::new(IEEE);
-    let mut writer = BitWriter::new();
+/// Bits taken by the symbols of `vlz`, extra bits included
+fn encoded_bits(vlz: &[LZ77], lenc: &[(Bits, u8)], denc: &[(Bits, u8)]) -> u64 {
+    vlz.iter()
+        .map(|lz| match *lz {
+            LZ77::Literal(l) => u64::from(lenc[l as usize].1),
+            LZ77::Copy { len, dist } => {
+                let lc = length_code(len).unwrap();
+                let dc = dist_code(dist).unwrap();
+                u64::from(lenc[lc.0].1 + lc.1 + denc[dc.0].1 + dc.1)
+            }
+        })
+        .sum()
+}
 
-    let mut lfreq = vec![0 as usize; MAX_NUM_LIT];
-    let mut dfreq = vec![0 as usize; MAX_DIST];
-    let mut read_len = 0;
-    let mut head = HashMap::<usize, usize>::new();
+/// Bits `write_stored` takes for `len` bytes when the writer is at bit `start`
+fn stored_bits(start: u64, len: usize) -> u64 {
+    let n = len.div_ceil(u16::MAX as usize).max(1) as u64;
+    // Only the first header is padded from wherever the writer is
+    let first = 3 + (8 - (start + 3) % 8) % 8;
+    first + (n - 1) * 8 + n * 32 + len as u64 * 8
+}
 
-    loop {
-        let len = input.read(&mut bytes).unwrap();
-        if len == 0 {
-            break;
-        }
-        if read_len == 0 {
-            writer.write_bits(1, 1);
-            writer.write_bits(BlockType::DynamicHuffman as u16, 2);
-        }
-        read_len += len;
-        let incr = if len >= MIN_LEN {
-            len - (MIN_LEN - 1)
-        } else {
-            0
-        };
-        if len >= MIN_LEN {
-            let mut prev = vec![len as usize; incr];
-            let mut skip_len = 0;
-            for (i, b) in bytes.windows(MIN_LEN).enumerate().take(incr) {
-                if skip_len > 0 {
-                    skip_len -= 1;
-                    continue;
-                }
-                let hash = trans24(b);
-                prev[i] = *(head.get(&hash).unwrap_or(&len));
-                let _ = head.insert(hash, i);
-                let (max_dist, max_len) = max_match(&bytes, &prev, len, i);
-                if max_len >= MIN_LEN {
-                    let dcode = dist_code(max_dist).unwrap().0;
-                    lfreq[length_code(max_len).unwrap().0] += 1;
-                    dfreq[dcode] += 1;
-                    info!("deflate copy {} {}", max_dist, max_len);
-                    debug!("dcode {}", dcode);
-                    debug!("copy {}", to_hex_string(b));
-                    vlz.push(LZ77::Copy {
-                        len: max_len,
-                        dist: max_dist,
-                    });
-                    skip_len = max_len - 1;
-                } else {
-                    lfreq[b[0] as usize] += 1;
-                    info!("deflate lit {:02x} at {}", b[0], i);
-                    vlz.push(LZ77::Literal(u16::from(b[0])));
-                }
-            }
-        }
+fn stored_report(len: usize) -> BlockReport {
+    BlockReport {
+        literals: len,
+        literal_bits: len as u64 * 8,
+        ..BlockReport::default()
+    }
+}
 
-        for b in bytes.iter().take(len).skip(incr) {
-            lfreq[*b as usize] += 1;
-            info!("deflate lit {:02x}", *b);
-            vlz.push(LZ77::Literal(u16::from(*b)));
+/// Write one Huffman block holding `vlz`, terminated with an end-of-block code
+///
+/// The dynamic code, the fixed code, or storing `data` as is, whichever is smallest,
+/// is used, as zlib does.
+fn write_block<W: Write>(
+    writer: &mut BitWriter<W>,
+    vlz: &[LZ77],
+    data: &[u8],
+    last: bool,
+    report: bool,
+) -> Result<Option<BlockReport>, Error> {
+    let mut lfreq = vec![0usize; MAX_NUM_LIT];
+    let mut dfreq = vec![0usize; NUM_DIST_CODE as usize];
+    for lz in vlz {
+        match *lz {
+            LZ77::Literal(l) => lfreq[l as usize] += 1,
+            LZ77::Copy { len, dist } => {
+                lfreq[length_code(len).unwrap().0] += 1;
+                dfreq[dist_code(dist).unwrap().0] += 1;
+            }
         }
     }
-    if read_len == 0 {
-        return Ok((0, 0));
-    }
+    lfreq[END_OF_BLOCK as usize] += 1;
     while lfreq.len() > MIN_NUM_LIT && *(lfreq.last().unwrap()) == 0 {
-        lfreq.pop(); //lfreq.resize(257, 0);//literals only
+        lfreq.pop();
     }
     while !dfreq.is_empty() && *(dfreq.last().unwrap()) == 0 {
         dfreq.pop();
     }
-    vlz.push(LZ77::Literal(END_OF_BLOCK));
-    lfreq[END_OF_BLOCK as usize] += 1;
-    debug!("read len {}", read_len);
-    let lit_clens = assign_lengths(&lfreq);
-    debug!("window {:?}", window);
-
+    ensure_two_codes(&mut dfreq);
+    let lit_clens = assign_limited_lengths(&lfreq, MAX_NUM_BITS);
     info!("dfreq {:?}", dfreq);
-    let mut dist_clens = assign_lengths(&dfreq);
+    let dist_clens = assign_limited_lengths(&dfreq, MAX_NUM_BITS);
     info!("dist_clens {:?}", dist_clens);
-    if dist_clens.is_empty() {
-        // No copy at all
-        dist_clens.push(0);
-    }
-    window.extend(write_code_table(&mut writer, &lit_clens, &dist_clens).iter());
-    debug!("window {:?}", window);
     let lenc = gen_huffman_enc(&lit_clens);
     let denc = gen_huffman_enc(&dist_clens);
+    let mut table = BitWriter::new();
+    write_code_table(&mut table, &lit_clens, &dist_clens)?;
+    let eob = END_OF_BLOCK as usize;
+    let dynamic_bits = table.bit_count() + encoded_bits(vlz, &lenc, &denc) + u64::from(lenc[eob].1);
+
+    let fixed_lit_clens = HuffmanEnc::fixed_literal_lengths();
+    let fixed_dist_clens = vec![5u8; NUM_DIST_CODE as usize];
+    let fixed_lenc = gen_huffman_enc(&fixed_lit_clens);
+    let fixed_denc = gen_huffman_enc(&fixed_dist_clens);
+    let fixed_bits = encoded_bits(vlz, &fixed_lenc, &fixed_denc) + u64::from(fixed_lenc[eob].1);
+
+    if stored_bits(writer.bit_count(), data.len()) < 3 + dynamic_bits.min(fixed_bits) {
+        write_stored(writer, data, last)?;
+        return Ok(if report {
+            Some(stored_report(data.len()))
+        } else {
+            None
+        });
+    }
+    writer.write_bits(last as u16, 1)?;
+    let (lit_clens, dist_clens, lenc, denc) = if fixed_bits < dynamic_bits {
+        writer.write_bits(BlockType::FixedHuffman as u16, 2)?;
+        (fixed_lit_clens, fixed_dist_clens, fixed_lenc, fixed_denc)
+    } else {
+        writer.write_bits(BlockType::DynamicHuffman as u16, 2)?;
+        write_code_table(writer, &lit_clens, &dist_clens)?;
+        (lit_clens, dist_clens, lenc, denc)
+    };
     info!("denc len {}", denc.len());
-    let vhuff = dehuffman(&vlz, &lenc, &denc);
+    let mut vhuff = dehuffman(vlz, &lenc, &denc);
+    vhuff.push(lenc[eob]);
     for (bits, bits_len) in vhuff {
-        let v = writer.write_bits(bits, bits_len);
-        window.extend(v.iter());
-        //debug!("window {:?}", window);
+        writer.write_bits(bits, bits_len)?;
     }
-    if let Some(c) = writer.flush() {
-        window.push(c);
+    let report = if report {
+        Some(block_report(vlz, &lenc, &denc, &lit_clens, &dist_clens))
+    } else {
+        None
+    };
+    Ok(report)
+}
+
+/// Write `data` as stored blocks of at most 64K each, the last one final if `last` is set
+fn write_stored<W: Write>(writer: &mut BitWriter<W>, data: &[u8], last: bool) -> Result<(), Error> {
+    let n = data.len().div_ceil(u16::MAX as usize);
+    for (i, chunk) in data.chunks(u16::MAX as usize).enumerate() {
+        writer.write_bits((last && i + 1 == n) as u16, 1)?;
+        writer.write_bits(BlockType::Store as u16, 2)?;
+        writer.align_to_byte();
+        let len = chunk.len() as u16;
+        writer.write_bytes(&len.to_le_bytes())?;
+        writer.write_bytes(&(!len).to_le_bytes())?;
+        writer.write_bytes(chunk)?;
     }
-    debug!("window {:?}", window);
-    output.write_all(&window)?;
-    hasher.write(&window[0..window.len()]);
-    let compressed_size = window.len();
-    debug!("compressed size: {}", compressed_size);
-    Ok((compressed_size as u32, hasher.sum32()))
+    Ok(())
+}
+
+/// Compress `data` into a single block, referring back into `dict` where it helps
+///
+/// With `Options::max_chain` at 0, or when compressing would make it bigger, the
+/// data goes into stored blocks instead.
+pub fn deflate_block<W: Write>(
+    writer: &mut BitWriter<W>,
+    dict: &[u8],
+    data: &[u8],
+    last: bool,
+    options: &Options,
+) -> Result<BlockInfo, Error> {
+    let start = writer.bit_count();
+    let mut report = if data.is_empty() {
+        // Fixed Huffman block holding nothing but the end-of-block code
+        writer.write_bits(last as u16, 1)?;
+        writer.write_bits(BlockType::FixedHuffman as u16, 2)?;
+        let (bits, bits_len) = FIXED_LITERAL_ENC[END_OF_BLOCK as usize];
+        writer.write_bits(bits, bits_len)?;
+        if options.report {
+            Some(BlockReport::default())
+        } else {
+            None
+        }
+    } else if options.max_chain == 0 {
+        write_stored(writer, data, last)?;
+        if options.report {
+            Some(stored_report(data.len()))
+        } else {
+            None
+        }
+    } else {
+        let dict = &dict[dict.len() - dict.len().min(MAX_DIST)..];
+        let mut bytes = Vec::with_capacity(dict.len() + data.len());
+        bytes.extend_from_slice(dict);
+        bytes.extend_from_slice(data);
+        let vlz = find_matches(&bytes, dict.len(), options);
+        write_block(writer, &vlz, data, last, options.report)?
+    };
+    let compressed_bits = writer.bit_count() - start;
+    if let Some(ref mut r) = report {
+        r.header_bits = compressed_bits - r.literal_bits - r.match_bits;
+    }
+    let info = BlockInfo {
+        uncompressed_size: data.len(),
+        compressed_bits,
+        last,
+        report,
+    };
+    Ok(info)
+}
+
+/// Terminate the current block with an empty stored block so the output ends on a byte boundary
+pub fn sync_flush<W: Write>(writer: &mut BitWriter<W>) -> Result<(), Error> {
+    writer.write_bits(0, 1)?;
+    writer.write_bits(BlockType::Store as u16, 2)?;
+    writer.align_to_byte();
+    writer.write_bytes(&[0x00, 0x00, 0xFF, 0xFF])
+}
+
+/// Compress `input` with the default options
+pub fn deflate<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+) -> Result<DeflateStats, Error> {
+    deflate_with(input, output, &Options::default())
+}
+
+pub fn deflate_with<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    options: &Options,
+) -> Result<DeflateStats, Error> {
+    let mut hasher = Crc32::new();
+    let mut writer = BitWriter::from_writer(output, BitOrder::Lsb);
+    let mut stats = DeflateStats::default();
+    let max_block = u16::MAX as usize;
+    let mut block_ends = BlockEnds::new(options.rsyncable, max_block);
+    let mut dict = Vec::<u8>::new();
+    // Input not compressed yet, starting where the last block ended
+    let mut pending = Vec::<u8>::new();
+    let mut data = read_chunk(input, max_block)?;
+    loop {
+        let next = read_chunk(input, max_block)?;
+        let last = next.is_empty();
+        hasher.update(&data);
+        pending.extend_from_slice(&data);
+        let ends = block_ends.next(&data, last);
+        let mut start = 0;
+        for (end, flush) in ends {
+            let segment = &pending[start..end];
+            let last_segment = last && end == pending.len();
+            let mut info = deflate_block(&mut writer, &dict, segment, last_segment, options)?;
+            if last_segment {
+                writer.align_to_byte();
+            } else if flush {
+                let before = writer.bit_count();
+                sync_flush(&mut writer)?;
+                info.add_flush_bits(writer.bit_count() - before);
+            }
+            dict.extend_from_slice(segment);
+            let excess = dict.len().saturating_sub(MAX_DIST);
+            dict.drain(0..excess);
+            stats.uncompressed_size += segment.len() as u64;
+            stats.blocks.push(info);
+            start = end;
+        }
+        pending.drain(0..start);
+        if last {
+            break;
+        }
+        data = next;
+    }
+    writer.flush()?;
+    stats.compressed_size = writer.bit_count() / 8;
+    stats.crc32 = hasher.value();
+    debug!("read len {}", stats.uncompressed_size);
+    debug!("compressed size: {}", stats.compressed_size);
+    Ok(stats)
 }
 
 fn dehuffman(vlz: &[LZ77], lenc: &[(Bits, u8)], denc: &[(Bits, u8)]) -> Vec<(Bits, u8)> {
@@ -564,7 +1084,7 @@ fn dehuffman(vlz: &[LZ77], lenc: &[(Bits, u8)], denc: &[(Bits, u8)]) -> Vec<(Bit
             LZ77::Copy { len: l, dist: d } => {
                 let lc = length_code(l).unwrap();
                 vhuff.push(lenc[lc.0]);
-                vhuff.push(((lc.0 & ((1 << lc.1) - 1)) as u16, lc.1));
+                vhuff.push(((l - LENGTH_BASE[lc.0 - 257]) as u16, lc.1));
                 let dc = dist_code(d).unwrap();
                 vhuff.push(denc[dc.0]);
                 vhuff.push((((d - 1) & ((1 << dc.1) - 1)) as u16, dc.1));
@@ -598,15 +1118,19 @@ mod test {
         let mut uncompressed = vec![0 as u8; uncompressed_len];
         rng.fill_bytes(&mut uncompressed);
         info!("uncompressed: {:?}", uncompressed);
-        let mut hasher = Digest::new(IEEE);
-        hasher.write(&uncompressed);
-        let crc = hasher.sum32();
+        let mut hasher = Crc32::new();
+        hasher.update(&uncompressed);
+        let crc = hasher.value();
         let mut compressed = Vec::<u8>::new();
         {
             let mut reader = BufReader::new(&uncompressed as &[u8]);
             let mut writer = BufWriter::new(&mut compressed);
-            let (compressed_len, ccrc) = deflate(&mut reader, &mut writer).unwrap();
-            debug!("compressed {} {}", compressed_len, ccrc);
+            let stats = deflate(&mut reader, &mut writer).unwrap();
+            debug!("compressed {}", stats.compressed_size);
+            assert_eq!(stats.uncompressed_size, uncompressed_len as u64);
+            assert_eq!(stats.crc32, crc);
+            let total_bits: u64 = stats.blocks.iter().map(|b| b.compressed_bits).sum();
+            assert_eq!(total_bits.div_ceil(8), stats.compressed_size);
             let _ = writer.flush();
         }
         if !compressed.is_empty() {
@@ -635,6 +1159,275 @@ mod test {
         end_to_end_test(uncompressed_len);
     }
 
+    fn compress(uncompressed: &[u8], options: &Options) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        {
+            let mut reader = BufReader::new(uncompressed);
+            let mut writer = BufWriter::new(&mut compressed);
+            deflate_with(&mut reader, &mut writer, options).unwrap();
+        }
+        let mut decompressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&compressed as &[u8]);
+            let mut writer = BufWriter::new(&mut decompressed);
+            inflate(&mut reader, &mut writer).unwrap();
+        }
+        assert_eq!(decompressed, uncompressed);
+        compressed
+    }
+
+    #[test]
+    fn repeat_across_codes() {
+        // The last literal/length lengths and the distance lengths are all 2, sent
+        // as one length and a repeat that runs on into the distance code
+        let mut lit_clens = vec![0u8; 258];
+        lit_clens[b'a' as usize] = 1;
+        lit_clens[END_OF_BLOCK as usize] = 2;
+        lit_clens[257] = 2;
+        let dist_clens = [2u8; 4];
+        let mut all = lit_clens.clone();
+        all.extend_from_slice(&dist_clens);
+        let eclens = encode_code_lengths(&all);
+        assert!(eclens.iter().any(|cl| matches!(
+            cl,
+            CodeLength::Repeat {
+                code: 16,
+                repeat: 2
+            }
+        )));
+        let mut freq = vec![0; HCLEN_ORDER.len()];
+        update_freq(&mut freq, &eclens);
+        let clen = assign_limited_lengths(&freq, MAX_CLEN_BITS);
+        let mapped_clens = reordered_code_lengths(&clen);
+
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1).unwrap();
+        writer
+            .write_bits(BlockType::DynamicHuffman as u16, 2)
+            .unwrap();
+        writer.write_bits(1, 5).unwrap();
+        writer.write_bits(3, 5).unwrap();
+        writer.write_bits(mapped_clens.len() as u16 - 4, 4).unwrap();
+        for l in &mapped_clens {
+            writer.write_bits(u16::from(*l), 3).unwrap();
+        }
+        write_code_lengths(&mut writer, &eclens, &gen_huffman_enc(&clen)).unwrap();
+        let lenc = gen_huffman_enc(&lit_clens);
+        let denc = gen_huffman_enc(&dist_clens);
+        for code in [lenc[b'a' as usize], lenc[257], denc[0], lenc[256]] {
+            writer.write_bits(code.0, code.1).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        let mut reader = BufReader::new(&compressed as &[u8]);
+        let mut decompressed = Vec::new();
+        {
+            let mut writer = BufWriter::new(&mut decompressed);
+            inflate(&mut reader, &mut writer).unwrap();
+        }
+        assert_eq!(decompressed, b"aaaa");
+    }
+
+    #[test]
+    fn unused_distance_code() {
+        // A block with one distance code, then a match using the bit pattern it leaves unused
+        let mut lit_clens = vec![0u8; 258];
+        lit_clens[b'a' as usize] = 1;
+        lit_clens[END_OF_BLOCK as usize] = 2;
+        lit_clens[257] = 2;
+        let lenc = gen_huffman_enc(&lit_clens);
+        let mut writer = BitWriter::new();
+        writer.write_bits(1, 1).unwrap();
+        writer
+            .write_bits(BlockType::DynamicHuffman as u16, 2)
+            .unwrap();
+        write_code_table(&mut writer, &lit_clens, &[1]).unwrap();
+        for symbol in [b'a' as usize, 257] {
+            writer.write_bits(lenc[symbol].0, lenc[symbol].1).unwrap();
+        }
+        writer.write_bits(1, 1).unwrap();
+        let compressed = writer.finish().unwrap();
+        let mut reader = BufReader::new(&compressed as &[u8]);
+        let mut writer = BufWriter::new(Vec::new());
+        let err = inflate(&mut reader, &mut writer).unwrap_err();
+        assert!(err.to_string().contains("Invalid Huffman code"));
+    }
+
+    #[test]
+    fn stats() {
+        let uncompressed = b"to be or not to be, that is the question".repeat(5000);
+        for options in [Options::level(1), Options::default(), Options::level(9)] {
+            let mut compressed = Vec::new();
+            let stats = {
+                let mut reader = BufReader::new(&uncompressed as &[u8]);
+                let mut writer = BufWriter::new(&mut compressed);
+                deflate_with(&mut reader, &mut writer, &options).unwrap()
+            };
+            assert_eq!(stats.uncompressed_size, uncompressed.len() as u64);
+            assert_eq!(stats.crc32, crate::crc32::crc32(&uncompressed));
+            assert_eq!(stats.compressed_size, compressed.len() as u64);
+            assert!(stats.blocks.len() > 1);
+        }
+    }
+
+    #[test]
+    fn levels() {
+        let uncompressed = b"to be or not to be, that is the question".repeat(2000);
+        let stored = compress(&uncompressed, &Options::level(0));
+        let blocks = uncompressed.len().div_ceil(u16::MAX as usize);
+        assert_eq!(stored.len(), uncompressed.len() + 5 * blocks);
+        assert_eq!(stored[5..45], uncompressed[..40]);
+        let mut previous = Options::level(1);
+        for level in 2..=7 {
+            let options = Options::level(level);
+            assert_eq!(options.finder, Finder::HashChain);
+            assert!(options.max_chain >= previous.max_chain);
+            assert!(options.nice_len >= previous.nice_len);
+            previous = options;
+        }
+        assert!(compress(&uncompressed, &Options::level(1)).len() < stored.len());
+    }
+
+    #[test]
+    fn incompressible() {
+        let mut rng = rand::thread_rng();
+        let uncompressed: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+        let compressed = compress(&uncompressed, &Options::default());
+        let blocks = uncompressed.len().div_ceil(u16::MAX as usize);
+        assert!(compressed.len() <= uncompressed.len() + 5 * blocks + 1);
+
+        // Too short for a dynamic code table to pay for itself
+        let short = compress(b"abcabc", &Options::default());
+        assert_eq!(short[0] >> 1 & 3, BlockType::FixedHuffman as u8);
+    }
+
+    #[test]
+    fn rsyncable() {
+        let mut rng = rand::thread_rng();
+        let mut uncompressed = vec![0u8; 200_000];
+        for b in uncompressed.iter_mut() {
+            *b = rng.gen_range(b'a', b'h');
+        }
+        let options = Options {
+            rsyncable: true,
+            ..Options::default()
+        };
+        let before = compress(&uncompressed, &options);
+        uncompressed[100] ^= 1;
+        let after = compress(&uncompressed, &options);
+        let common = before
+            .iter()
+            .rev()
+            .zip(after.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common > before.len() / 2);
+    }
+
+    #[test]
+    fn rsyncable_overhead() {
+        // Letters averaging 100, so the rolling sum keeps landing on boundaries
+        let mut rng = rand::thread_rng();
+        let uncompressed: Vec<u8> = (0..1_000_000).map(|_| rng.gen_range(b'a', b'h')).collect();
+        let plain = compress(&uncompressed, &Options::default()).len();
+        let options = Options {
+            rsyncable: true,
+            ..Options::default()
+        };
+        let rsyncable = compress(&uncompressed, &options).len();
+        assert!(rsyncable <= plain + plain / 100);
+    }
+
+    #[test]
+    fn rsyncable_insert() {
+        let mut rng = rand::thread_rng();
+        let mut uncompressed = vec![0u8; 600_000];
+        for b in uncompressed.iter_mut() {
+            *b = rng.gen_range(b'a', b'h');
+        }
+        let options = Options {
+            rsyncable: true,
+            ..Options::default()
+        };
+        let before = compress(&uncompressed, &options);
+        let inserted = b"inserted text";
+        let at = 70_000;
+        let mut edited = uncompressed[..at].to_vec();
+        edited.extend_from_slice(inserted);
+        edited.extend_from_slice(&uncompressed[at..]);
+        let after = compress(&edited, &options);
+        // A window past the edit the rolling sums agree again, and past another
+        // MAX_DIST no match reaches back to the edit, so the first boundary after
+        // that brings the output back in step
+        let common = before
+            .iter()
+            .rev()
+            .zip(after.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common > after.len() / 2);
+    }
+
+    fn assert_report_adds_up(stats: &DeflateStats) {
+        for block in &stats.blocks {
+            let report = block.report.as_ref().unwrap();
+            assert_eq!(
+                report.header_bits + report.literal_bits + report.match_bits,
+                block.compressed_bits
+            );
+        }
+        let total_bits: u64 = stats.blocks.iter().map(|b| b.compressed_bits).sum();
+        assert_eq!(total_bits.div_ceil(8), stats.compressed_size);
+    }
+
+    #[test]
+    fn report_with_flushes() {
+        let mut rng = rand::thread_rng();
+        let uncompressed: Vec<u8> = (0..100_000).map(|_| rng.gen_range(b'a', b'h')).collect();
+        for level in [0, 6] {
+            let options = Options {
+                rsyncable: true,
+                report: true,
+                ..Options::level(level)
+            };
+            let mut compressed = Vec::new();
+            let stats = {
+                let mut reader = BufReader::new(&uncompressed as &[u8]);
+                let mut writer = BufWriter::new(&mut compressed);
+                deflate_with(&mut reader, &mut writer, &options).unwrap()
+            };
+            assert!(stats.blocks.len() > 2);
+            assert_report_adds_up(&stats);
+        }
+    }
+
+    #[test]
+    fn report() {
+        let uncompressed = b"to be or not to be, that is the question".repeat(100);
+        let options = Options {
+            report: true,
+            ..Options::default()
+        };
+        let mut compressed = Vec::new();
+        let stats = {
+            let mut reader = BufReader::new(&uncompressed as &[u8]);
+            let mut writer = BufWriter::new(&mut compressed);
+            deflate_with(&mut reader, &mut writer, &options).unwrap()
+        };
+        let block = &stats.blocks[0];
+        let report = block.report.as_ref().unwrap();
+        assert_report_adds_up(&stats);
+        let matched: usize = report
+            .length_histogram
+            .iter()
+            .enumerate()
+            .map(|(len, n)| len * n)
+            .sum();
+        assert_eq!(report.literals + matched, uncompressed.len());
+        assert_eq!(report.matches, report.distance_histogram.iter().sum());
+        assert!(report.lit_code_lengths[END_OF_BLOCK as usize] > 0);
+        assert!(!report.dist_code_lengths.is_empty());
+    }
+
     #[test]
     fn codelen_alphabet() {
         env_logger::try_init();
@@ -688,18 +1481,15 @@ mod test {
         let mapped_clens = reordered_code_lengths(&clens);
         let hclen = mapped_clens.len();
         let mut writer = BitWriter::new();
-        let mut encoded = Vec::new();
-        {
-            encoded.extend(writer.write_bits(hclen as u16 - 4, 4).iter());
-            debug!("{:?}", clens);
-            for i in 0..hclen {
-                encoded.extend(writer.write_bits(mapped_clens[i] as u16, 3).iter());
-                debug!("{}->{}", HCLEN_ORDER[i], mapped_clens[i]);
-            }
-            let enc = gen_huffman_enc(&clens);
-            encoded.extend(write_code_lengths(&mut writer, &eclens, &enc));
-            encoded.extend(writer.flush());
+        writer.write_bits(hclen as u16 - 4, 4).unwrap();
+        debug!("{:?}", clens);
+        for i in 0..hclen {
+            writer.write_bits(mapped_clens[i] as u16, 3).unwrap();
+            debug!("{}->{}", HCLEN_ORDER[i], mapped_clens[i]);
         }
+        let enc = gen_huffman_enc(&clens);
+        write_code_lengths(&mut writer, &eclens, &enc).unwrap();
+        let encoded = writer.finish().unwrap();
         let mut input = BufReader::new(&encoded as &[u8]);
         let mut reader = BitReader::new(&mut input);
         let hclen = reader.read_bits(4, true).unwrap() as usize + 4;
@@ -710,7 +1500,7 @@ mod test {
             debug!("{}->{}", i, hclen_len[HCLEN_ORDER[i]]);
         }
         debug!("{:?}", hclen_len);
-        let clen_dec = gen_huffman_dec(&hclen_len, max_hclen as u16);
+        let clen_dec = gen_huffman_dec(&hclen_len, max_hclen as u16).unwrap();
         debug!("{:?}", clen_dec);
         let hlit_len = read_code_lengths(&mut reader, &clen_dec, len).unwrap();
         assert_eq!(v.len(), hlit_len.len());
@@ -1,14 +1,20 @@
+use std::ffi::OsString;
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::io::{self, BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom};
 use std::mem::transmute;
-use std::str;
-
-use num::FromPrimitive;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use crate::bitstream::{BitOrder, BitWriter};
+use crate::constant::MAX_DIST;
+use crate::crc32::{crc32, Crc32};
 use crate::deflate::*;
 
+const MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+#[derive(Clone, Debug, Default)]
 struct Flags {
     ftext: bool,
     fhcrc: bool,
@@ -17,33 +23,107 @@ struct Flags {
     fcomment: bool,
 }
 
-#[repr(u8)]
-#[derive(FromPrimitive)]
-enum ExtraFlags {
-    Ignored = 0,
-    Maximum = 2,
-    Fastest = 4,
+/// Compression level hint in the XFL byte
+///
+/// Values RFC 1952 doesn't define are kept as `Other`, as readers are to ignore them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtraFlags {
+    Ignored,
+    Maximum,
+    Fastest,
+    Other(u8),
+}
+
+impl From<u8> for ExtraFlags {
+    fn from(byte: u8) -> ExtraFlags {
+        match byte {
+            0 => ExtraFlags::Ignored,
+            2 => ExtraFlags::Maximum,
+            4 => ExtraFlags::Fastest,
+            b => ExtraFlags::Other(b),
+        }
+    }
+}
+
+impl From<ExtraFlags> for u8 {
+    fn from(xfl: ExtraFlags) -> u8 {
+        match xfl {
+            ExtraFlags::Ignored => 0,
+            ExtraFlags::Maximum => 2,
+            ExtraFlags::Fastest => 4,
+            ExtraFlags::Other(b) => b,
+        }
+    }
 }
 
-#[repr(u8)]
-#[derive(FromPrimitive)]
+/// File system the member was compressed on
+///
+/// OS bytes missing from RFC 1952's table are kept as `Other`.
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[allow(clippy::enum_variant_names)]
-enum OS {
-    FAT = 0,
-    Amiga = 1,
-    VMS = 2,
-    UNIX = 3,
-    VMCMS = 4,
-    AtariTOS = 5,
-    HPFS = 6,
-    Macintosh = 7,
-    ZSystem = 8,
-    CPM = 9,
-    TOPS20 = 10,
-    NTFS = 11,
-    QDOS = 12,
-    AcornRISCOS = 13,
-    Unknown = 255,
+pub enum OS {
+    FAT,
+    Amiga,
+    VMS,
+    UNIX,
+    VMCMS,
+    AtariTOS,
+    HPFS,
+    Macintosh,
+    ZSystem,
+    CPM,
+    TOPS20,
+    NTFS,
+    QDOS,
+    AcornRISCOS,
+    Unknown,
+    Other(u8),
+}
+
+impl From<u8> for OS {
+    fn from(byte: u8) -> OS {
+        match byte {
+            0 => OS::FAT,
+            1 => OS::Amiga,
+            2 => OS::VMS,
+            3 => OS::UNIX,
+            4 => OS::VMCMS,
+            5 => OS::AtariTOS,
+            6 => OS::HPFS,
+            7 => OS::Macintosh,
+            8 => OS::ZSystem,
+            9 => OS::CPM,
+            10 => OS::TOPS20,
+            11 => OS::NTFS,
+            12 => OS::QDOS,
+            13 => OS::AcornRISCOS,
+            255 => OS::Unknown,
+            b => OS::Other(b),
+        }
+    }
+}
+
+impl From<OS> for u8 {
+    fn from(os: OS) -> u8 {
+        match os {
+            OS::FAT => 0,
+            OS::Amiga => 1,
+            OS::VMS => 2,
+            OS::UNIX => 3,
+            OS::VMCMS => 4,
+            OS::AtariTOS => 5,
+            OS::HPFS => 6,
+            OS::Macintosh => 7,
+            OS::ZSystem => 8,
+            OS::CPM => 9,
+            OS::TOPS20 => 10,
+            OS::NTFS => 11,
+            OS::QDOS => 12,
+            OS::AcornRISCOS => 13,
+            OS::Unknown => 255,
+            OS::Other(b) => b,
+        }
+    }
 }
 
 impl fmt::Display for OS {
@@ -63,12 +143,106 @@ impl fmt::Display for OS {
             OS::NTFS => write!(f, "NTFS"),
             OS::QDOS => write!(f, "QDOS"),
             OS::AcornRISCOS => write!(f, "Acron RISCOS"),
-            _ => write!(f, "Unknown"),
+            OS::Unknown => write!(f, "Unknown"),
+            OS::Other(b) => write!(f, "Unknown ({})", b),
         }
     }
 }
 
-#[allow(dead_code)]
+/// One `SI1 SI2 LEN data` entry of the FEXTRA field
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtraSubfield {
+    pub si1: u8,
+    pub si2: u8,
+    pub data: Vec<u8>,
+}
+
+/// Subfields with a known layout
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subfield {
+    /// BGZF `BC`: total block size minus one
+    Bgzf(u16),
+    /// dictzip `RA`: uncompressed chunk length and the compressed size of each chunk
+    Dictzip {
+        chunk_length: u16,
+        chunk_sizes: Vec<u16>,
+    },
+}
+
+impl ExtraSubfield {
+    pub fn new(si1: u8, si2: u8, data: &[u8]) -> ExtraSubfield {
+        ExtraSubfield {
+            si1,
+            si2,
+            data: data.to_vec(),
+        }
+    }
+
+    /// Decode the subfield if its ID and layout are known
+    pub fn decode(&self) -> Option<Subfield> {
+        let words: Vec<u16> = self
+            .data
+            .chunks_exact(2)
+            .map(|w| u16::from_le_bytes([w[0], w[1]]))
+            .collect();
+        match (self.si1, self.si2) {
+            (b'B', b'C') if self.data.len() == 2 => Some(Subfield::Bgzf(words[0])),
+            (b'R', b'A')
+                if self.data.len() % 2 == 0
+                    && words.len() >= 3
+                    && words[0] == 1
+                    && words.len() == 3 + words[2] as usize =>
+            {
+                Some(Subfield::Dictzip {
+                    chunk_length: words[1],
+                    chunk_sizes: words[3..].to_vec(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<Subfield> for ExtraSubfield {
+    fn from(subfield: Subfield) -> ExtraSubfield {
+        match subfield {
+            Subfield::Bgzf(bsize) => ExtraSubfield::new(b'B', b'C', &bsize.to_le_bytes()),
+            Subfield::Dictzip {
+                chunk_length,
+                chunk_sizes,
+            } => {
+                let mut data = Vec::with_capacity(6 + 2 * chunk_sizes.len());
+                data.extend_from_slice(&1u16.to_le_bytes());
+                data.extend_from_slice(&chunk_length.to_le_bytes());
+                data.extend_from_slice(&(chunk_sizes.len() as u16).to_le_bytes());
+                for size in chunk_sizes {
+                    data.extend_from_slice(&size.to_le_bytes());
+                }
+                ExtraSubfield::new(b'R', b'A', &data)
+            }
+        }
+    }
+}
+
+/// Split an FEXTRA field into its subfields
+pub fn parse_extra(mut extra: &[u8]) -> Result<Vec<ExtraSubfield>, Error> {
+    let mut subfields = Vec::new();
+    while !extra.is_empty() {
+        if extra.len() < 4 {
+            return Err(Error::new(ErrorKind::Other, "Truncated extra subfield"));
+        }
+        let len = u16::from_le_bytes([extra[2], extra[3]]) as usize;
+        if extra.len() < 4 + len {
+            return Err(Error::new(ErrorKind::Other, "Truncated extra subfield"));
+        }
+        subfields.push(ExtraSubfield::new(extra[0], extra[1], &extra[4..4 + len]));
+        extra = &extra[4 + len..];
+    }
+    Ok(subfields)
+}
+
+/// Header and trailer of one gzip member
+#[derive(Clone, Debug)]
 pub struct GzipMember {
     flg: Flags,
     xfl: ExtraFlags,
@@ -78,151 +252,936 @@ pub struct GzipMember {
     offset: u64,
     crc32: u32,
     isize: u32,
+    compressed_size: u64,
+    index: usize,
     file_name: String,
     file_comment: String,
+    // FNAME and FCOMMENT as stored, which gzip says are ISO 8859-1
+    raw_file_name: Vec<u8>,
+    raw_comment: Vec<u8>,
+    extra: Vec<u8>,
 }
 
-pub fn parse(file_name: &str) -> Result<Vec<GzipMember>, Error> {
-    let file = File::open(file_name)?;
-    let mut reader = BufReader::new(file);
-    let mut byte: [u8; 1] = [0; 1];
-    let mut word: [u8; 2] = [0; 2];
-    let mut dword: [u8; 4] = [0; 4];
-    let mut members = Vec::new();
-    //let current = reader.seek(SeekFrom::Current(0)).unwrap();
-    let end = reader.seek(SeekFrom::End(0)).unwrap();
-    //assert_eq!(current, reader.seek(SeekFrom::Start(current)).unwrap());
-    let _ = reader.seek(SeekFrom::Start(0));
-    while reader.seek(SeekFrom::Current(0)).unwrap() != end {
-        reader.read_exact(&mut byte)?;
-        assert_eq!(byte[0], 0x1F);
-        reader.read_exact(&mut byte)?;
-        assert_eq!(byte[0], 0x8B);
-        reader.read_exact(&mut byte)?;
-        assert_eq!(byte[0], 8); //Deflate Only
-        reader.read_exact(&mut byte)?;
-        let mut flg = Flags {
-            ftext: false,
-            fhcrc: false,
-            fextra: false,
-            fname: false,
-            fcomment: false,
-        };
-        if byte[0] & 1 == 1 {
-            flg.ftext = true;
+impl Default for GzipMember {
+    fn default() -> GzipMember {
+        GzipMember::new()
+    }
+}
+
+impl GzipMember {
+    /// Header with no name, comment or modification time, for writing a member
+    pub fn new() -> GzipMember {
+        GzipMember {
+            flg: Flags::default(),
+            xfl: ExtraFlags::Ignored,
+            mtime: 0,
+            os: OS::Unknown,
+            crc16: 0,
+            offset: 0,
+            crc32: 0,
+            isize: 0,
+            compressed_size: 0,
+            index: 0,
+            file_name: String::new(),
+            file_comment: String::new(),
+            raw_file_name: Vec::new(),
+            raw_comment: Vec::new(),
+            extra: Vec::new(),
         }
-        if byte[0] & 2 == 2 {
-            flg.fhcrc = true;
+    }
+
+    /// Original file name, lossily decoded, or the name derived from the gzip file if the header has none
+    pub fn file_name(&self) -> &str {
+        &self.file_name
+    }
+
+    /// FNAME exactly as stored, empty if the header has none
+    pub fn file_name_bytes(&self) -> &[u8] {
+        &self.raw_file_name
+    }
+
+    pub fn comment(&self) -> &str {
+        &self.file_comment
+    }
+
+    /// FCOMMENT exactly as stored, empty if the header has none
+    pub fn comment_bytes(&self) -> &[u8] {
+        &self.raw_comment
+    }
+
+    /// Modification time, `None` if the header doesn't record one
+    pub fn mtime(&self) -> Option<SystemTime> {
+        match self.mtime {
+            0 => None,
+            t => Some(UNIX_EPOCH + Duration::from_secs(u64::from(t))),
         }
-        if byte[0] & 4 == 4 {
-            flg.fextra = true;
+    }
+
+    pub fn os(&self) -> OS {
+        self.os
+    }
+
+    /// How hard the compressor says it tried
+    pub fn compression_hint(&self) -> ExtraFlags {
+        self.xfl
+    }
+
+    /// Whether the compressor thought the content is text
+    pub fn is_text(&self) -> bool {
+        self.flg.ftext
+    }
+
+    /// Stored header CRC-16, if FHCRC is set
+    pub fn header_crc(&self) -> Option<u16> {
+        if self.flg.fhcrc {
+            Some(self.crc16)
+        } else {
+            None
         }
-        if byte[0] & 8 == 8 {
-            flg.fname = true;
+    }
+
+    /// Raw extra field, empty if there is none
+    pub fn extra(&self) -> &[u8] {
+        &self.extra
+    }
+
+    /// CRC-32 of the uncompressed data, from the trailer
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    /// Uncompressed size modulo 2^32, from the trailer
+    pub fn uncompressed_size(&self) -> u32 {
+        self.isize
+    }
+
+    /// Size of the whole member including header and trailer, 0 if it wasn't measured
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Offset of the compressed data in the gzip stream
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Store an original file name, or none if empty
+    pub fn set_file_name(&mut self, name: &str) -> Result<(), Error> {
+        self.set_file_name_bytes(name.as_bytes())
+    }
+
+    /// Store an original file name that needn't be UTF-8, or none if empty
+    pub fn set_file_name_bytes(&mut self, name: &[u8]) -> Result<(), Error> {
+        if name.contains(&0) {
+            return Err(Error::new(ErrorKind::Other, "File name contains a NUL"));
         }
-        if byte[0] & 16 == 16 {
-            flg.fcomment = true;
+        self.flg.fname = !name.is_empty();
+        self.file_name = String::from_utf8_lossy(name).into_owned();
+        self.raw_file_name = name.to_vec();
+        Ok(())
+    }
+
+    /// Store a comment, or none if empty
+    pub fn set_comment(&mut self, comment: &str) -> Result<(), Error> {
+        if comment.contains('\0') {
+            return Err(Error::new(ErrorKind::Other, "Comment contains a NUL"));
         }
-        reader.read_exact(&mut dword)?;
-        let mtime = trans_bytes!(dword);
-        reader.read_exact(&mut byte)?;
-        let xfl = match ExtraFlags::from_u8(byte[0]) {
-            Some(x) => x,
-            None => return Err(Error::new(ErrorKind::Other, "Bad XFL")),
-        };
+        self.flg.fcomment = !comment.is_empty();
+        self.file_comment = String::from(comment);
+        self.raw_comment = comment.as_bytes().to_vec();
+        Ok(())
+    }
 
-        reader.read_exact(&mut byte)?;
-        let os = match OS::from_u8(byte[0]) {
-            Some(x) => x,
-            None => return Err(Error::new(ErrorKind::Other, "Bad XFL")),
-        };
-        if flg.fextra {
-            reader.read_exact(&mut word)?;
-            let xlen: u16 = trans_bytes!(word);
-            let mut extra = vec![0 as u8; xlen as usize];
-            reader.read_exact(&mut extra as &mut [u8])?;
-        }
-        let file_name = if flg.fname {
-            let mut v = Vec::<u8>::new();
-            reader.read_until(0, &mut v)?;
-            v.pop(); //Remove trailing '\0'
-            String::from_utf8(v).unwrap()
-        } else {
-            let mut tmp = String::from(file_name);
-            if tmp.ends_with(".gz") {
-                let len = tmp.len() - 3;
-                tmp.truncate(len);
-            }
-            if !members.is_empty() {
-                tmp += &format!(".{}", members.len());
+    /// Modification time in seconds since the epoch, 0 for none
+    pub fn set_mtime(&mut self, mtime: u32) {
+        self.mtime = mtime;
+    }
+
+    pub fn set_os(&mut self, os: OS) {
+        self.os = os;
+    }
+
+    pub fn set_extra_flags(&mut self, xfl: ExtraFlags) {
+        self.xfl = xfl;
+    }
+
+    /// Mark the content as probably text
+    pub fn set_text(&mut self, text: bool) {
+        self.flg.ftext = text;
+    }
+
+    /// Store a raw extra field, or none if empty
+    pub fn set_extra(&mut self, extra: &[u8]) -> Result<(), Error> {
+        if extra.len() > u16::MAX as usize {
+            return Err(Error::new(ErrorKind::Other, "Extra field too long"));
+        }
+        self.flg.fextra = !extra.is_empty();
+        self.extra = extra.to_vec();
+        Ok(())
+    }
+
+    /// Subfields of the extra field, empty if there is none
+    pub fn subfields(&self) -> Result<Vec<ExtraSubfield>, Error> {
+        parse_extra(&self.extra)
+    }
+
+    /// Replace the extra field with `subfields`
+    pub fn set_subfields(&mut self, subfields: &[ExtraSubfield]) -> Result<(), Error> {
+        let mut extra = Vec::new();
+        for sub in subfields {
+            if sub.data.len() > u16::MAX as usize {
+                return Err(Error::new(ErrorKind::Other, "Extra subfield too long"));
             }
-            tmp
+            extra.push(sub.si1);
+            extra.push(sub.si2);
+            extra.extend_from_slice(&(sub.data.len() as u16).to_le_bytes());
+            extra.extend_from_slice(&sub.data);
+        }
+        self.set_extra(&extra)
+    }
+
+    /// Protect the header with a CRC-16
+    pub fn set_header_crc(&mut self, fhcrc: bool) {
+        self.flg.fhcrc = fhcrc;
+    }
+
+    /// Encode the header as written before the compressed data
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut v = vec![MAGIC[0], MAGIC[1], 8];
+        let flg = self.flg.ftext as u8
+            | (self.flg.fhcrc as u8) << 1
+            | (self.flg.fextra as u8) << 2
+            | (self.flg.fname as u8) << 3
+            | (self.flg.fcomment as u8) << 4;
+        v.push(flg);
+        v.extend_from_slice(&self.mtime.to_le_bytes());
+        v.push(u8::from(self.xfl));
+        v.push(u8::from(self.os));
+        if self.flg.fextra {
+            v.extend_from_slice(&(self.extra.len() as u16).to_le_bytes());
+            v.extend_from_slice(&self.extra);
+        }
+        if self.flg.fname {
+            v.extend_from_slice(&self.raw_file_name);
+            v.push(0);
+        }
+        if self.flg.fcomment {
+            v.extend_from_slice(&self.raw_comment);
+            v.push(0);
+        }
+        if self.flg.fhcrc {
+            let crc16 = crc32(&v) as u16;
+            v.extend_from_slice(&crc16.to_le_bytes());
+        }
+        v
+    }
+}
+
+/// The FHCRC stored in a header doesn't match the header bytes
+#[derive(Debug, PartialEq)]
+pub struct HeaderCrcError {
+    pub stored: u16,
+    pub computed: u16,
+}
+
+impl fmt::Display for HeaderCrcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Wrong header CRC-16 {:04x}, expected {:04x}",
+            self.stored, self.computed
+        )
+    }
+}
+
+impl std::error::Error for HeaderCrcError {}
+
+/// Name for member `index` of `file_name` when the header's name isn't used
+fn default_name(file_name: &str, index: usize) -> String {
+    let mut name = String::from(file_name);
+    if name.ends_with(".gz") {
+        let len = name.len() - 3;
+        name.truncate(len);
+    }
+    if index > 0 {
+        name += &format!(".{}", index);
+    }
+    name
+}
+
+/// Read a member header, naming the member after `file_name` if it has no FNAME
+fn read_header<R: BufRead>(
+    reader: &mut R,
+    file_name: &str,
+    index: usize,
+) -> Result<GzipMember, Error> {
+    let mut magic: [u8; 2] = [0; 2];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(Error::new(ErrorKind::Other, "Not a gzip member"));
+    }
+    read_header_after_magic(reader, file_name, index)
+}
+
+/// Read the rest of a member header once its magic bytes have gone by
+fn read_header_after_magic<R: BufRead>(
+    reader: &mut R,
+    file_name: &str,
+    index: usize,
+) -> Result<GzipMember, Error> {
+    let mut byte: [u8; 1] = [0; 1];
+    let mut word: [u8; 2] = [0; 2];
+    let mut dword: [u8; 4] = [0; 4];
+    let mut hcrc = Crc32::new();
+    hcrc.update(&MAGIC);
+    reader.read_exact(&mut byte)?;
+    hcrc.update(&byte);
+    if byte[0] != 8 {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Unsupported compression method",
+        ));
+    }
+    reader.read_exact(&mut byte)?;
+    hcrc.update(&byte);
+    let mut flg = Flags::default();
+    if byte[0] & 1 == 1 {
+        flg.ftext = true;
+    }
+    if byte[0] & 2 == 2 {
+        flg.fhcrc = true;
+    }
+    if byte[0] & 4 == 4 {
+        flg.fextra = true;
+    }
+    if byte[0] & 8 == 8 {
+        flg.fname = true;
+    }
+    if byte[0] & 16 == 16 {
+        flg.fcomment = true;
+    }
+    reader.read_exact(&mut dword)?;
+    hcrc.update(&dword);
+    let mtime = trans_bytes!(dword);
+    reader.read_exact(&mut byte)?;
+    hcrc.update(&byte);
+    let xfl = ExtraFlags::from(byte[0]);
+
+    reader.read_exact(&mut byte)?;
+    hcrc.update(&byte);
+    let os = OS::from(byte[0]);
+    let mut extra = Vec::new();
+    if flg.fextra {
+        reader.read_exact(&mut word)?;
+        hcrc.update(&word);
+        let xlen: u16 = trans_bytes!(word);
+        extra = vec![0 as u8; xlen as usize];
+        reader.read_exact(&mut extra as &mut [u8])?;
+        hcrc.update(&extra);
+    }
+    let mut raw_file_name = Vec::new();
+    let file_name = if flg.fname {
+        reader.read_until(0, &mut raw_file_name)?;
+        hcrc.update(&raw_file_name);
+        raw_file_name.pop(); //Remove trailing '\0'
+        String::from_utf8_lossy(&raw_file_name).into_owned()
+    } else {
+        default_name(file_name, index)
+    };
+    debug!("File name: {}", file_name);
+    let mut raw_comment = Vec::new();
+    let mut file_comment = String::new();
+    if flg.fcomment {
+        reader.read_until(0, &mut raw_comment)?;
+        hcrc.update(&raw_comment);
+        raw_comment.pop();
+        file_comment = String::from_utf8_lossy(&raw_comment).into_owned();
+        debug!("File comment: {}", file_comment);
+    }
+    let crc16: u16 = if flg.fhcrc {
+        reader.read_exact(&mut word)?;
+        let stored: u16 = trans_bytes!(word);
+        let computed = hcrc.value() as u16;
+        if stored != computed {
+            return Err(Error::new(
+                ErrorKind::Other,
+                HeaderCrcError { stored, computed },
+            ));
+        }
+        stored
+    } else {
+        0
+    };
+    Ok(GzipMember {
+        flg,
+        mtime,
+        xfl,
+        os,
+        crc16,
+        crc32: 0,
+        isize: 0,
+        compressed_size: 0,
+        offset: 0,
+        index,
+        file_name,
+        file_comment,
+        raw_file_name,
+        raw_comment,
+        extra,
+    })
+}
+
+/// What to do with data after the last member that doesn't look like another member
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingPolicy {
+    /// Fail, as `read_header` would
+    Strict,
+    /// Stop at it and report it, like GNU gzip's "trailing garbage ignored"
+    Tolerant,
+}
+
+/// What followed the last member
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrailingData {
+    Nothing,
+    /// Zero padding, as left by tape drives and some uploaders
+    Zeros(u64),
+    Garbage(u64),
+}
+
+/// Start of the next member, or what came after the last one
+enum Next {
+    Member(GzipMember),
+    End(TrailingData),
+}
+
+/// Read the next member header, deciding what to make of anything that isn't one
+fn next_member<R: BufRead>(
+    reader: &mut R,
+    file_name: &str,
+    index: usize,
+    policy: TrailingPolicy,
+) -> Result<Next, Error> {
+    let mut magic: [u8; 2] = [0; 2];
+    let mut n = 0;
+    while n < magic.len() {
+        match reader.read(&mut magic[n..])? {
+            0 => break,
+            k => n += k,
+        }
+    }
+    if n == 0 {
+        return Ok(Next::End(TrailingData::Nothing));
+    }
+    if magic[..n] == MAGIC {
+        return read_header_after_magic(reader, file_name, index).map(Next::Member);
+    }
+    // The first member has to be there whatever the policy
+    if index == 0 || policy == TrailingPolicy::Strict {
+        return Err(Error::new(ErrorKind::Other, "Not a gzip member"));
+    }
+    let mut zeros = magic[..n].iter().all(|b| *b == 0);
+    let mut count = n as u64;
+    loop {
+        let len = {
+            let buf = reader.fill_buf()?;
+            zeros = zeros && buf.iter().all(|b| *b == 0);
+            buf.len()
         };
-        debug!("File name: {}", file_name);
-        let mut file_comment = String::new();
-        if flg.fcomment {
-            let mut v = Vec::<u8>::new();
-            reader.read_until(0, &mut v)?;
-            v.pop();
-            file_comment = String::from_utf8(v).unwrap();
-            debug!("File comment: {}", file_comment);
-        }
-        let crc16: u16 = if flg.fhcrc {
-            reader.read_exact(&mut word)?;
-            trans_bytes!(word)
-        } else {
-            0
+        if len == 0 {
+            break;
+        }
+        count += len as u64;
+        reader.consume(len);
+    }
+    if zeros {
+        info!("Trailing zero bytes ignored: {}", count);
+        Ok(Next::End(TrailingData::Zeros(count)))
+    } else {
+        info!("Trailing garbage ignored: {} bytes", count);
+        Ok(Next::End(TrailingData::Garbage(count)))
+    }
+}
+
+/// Read a member trailer and check it against what was decompressed
+fn read_trailer<R: Read>(
+    reader: &mut R,
+    member: &mut GzipMember,
+    decompressed_size: u32,
+    crc: u32,
+) -> Result<(), Error> {
+    let mut dword: [u8; 4] = [0; 4];
+    reader.read_exact(&mut dword)?;
+    member.crc32 = trans_bytes!(dword);
+    reader.read_exact(&mut dword)?;
+    member.isize = trans_bytes!(dword);
+    debug!(
+        "{}({:08x}), expected {}({:08x})",
+        decompressed_size, crc, member.isize, member.crc32
+    );
+    if crc != member.crc32 {
+        return Err(Error::new(ErrorKind::Other, "Wrong CRC-32"));
+    }
+    if decompressed_size != member.isize {
+        return Err(Error::new(ErrorKind::Other, "Wrong uncompressed size"));
+    }
+    Ok(())
+}
+
+/// Reader that keeps count of the bytes read through it
+struct Counted<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Position of `reader` in the underlying stream
+fn position<R: Read>(reader: &BufReader<Counted<R>>) -> u64 {
+    reader.get_ref().count - reader.buffer().len() as u64
+}
+
+/// Walk the members of a gzip file, checking each one but keeping none of the output
+pub fn parse(file_name: &str) -> Result<Vec<GzipMember>, Error> {
+    list(File::open(file_name)?, file_name)
+}
+
+/// Walk the members of a gzip stream in a single pass, recording their offsets and sizes
+///
+/// Members without FNAME are named after `file_name`, as `parse` does.
+pub fn list<R: Read>(input: R, file_name: &str) -> Result<Vec<GzipMember>, Error> {
+    Ok(list_with(input, file_name, TrailingPolicy::Strict)?.0)
+}
+
+/// `list` that deals with data after the last member according to `policy`
+pub fn list_with<R: Read>(
+    input: R,
+    file_name: &str,
+    policy: TrailingPolicy,
+) -> Result<(Vec<GzipMember>, TrailingData), Error> {
+    let mut reader = BufReader::new(Counted {
+        inner: input,
+        count: 0,
+    });
+    let mut members = Vec::new();
+    loop {
+        let start = position(&reader);
+        let mut mem = match next_member(&mut reader, file_name, members.len(), policy)? {
+            Next::Member(mem) => mem,
+            Next::End(trailing) => return Ok((members, trailing)),
         };
-        let offset = reader.seek(SeekFrom::Current(0)).unwrap();
-        let out = Vec::<u8>::new();
-        let mut writer = BufWriter::new(out);
+        mem.offset = position(&reader);
+        let mut writer = BufWriter::new(io::sink());
         let (decompressed_size, crc) = inflate(&mut reader, &mut writer)?;
-        reader.read_exact(&mut dword)?;
-        let out = match writer.into_inner() {
-            Ok(x) => x,
-            Err(_) => return Err(Error::new(ErrorKind::Other, "Can't get the inner output")),
-        };
-        let crc32: u32 = trans_bytes!(dword);
-        reader.read_exact(&mut dword)?;
-        let isize: u32 = trans_bytes!(dword);
+        read_trailer(&mut reader, &mut mem, decompressed_size, crc)?;
+        mem.compressed_size = position(&reader) - start;
         debug!(
-            "{}({:08x}), expected {}({:08x})",
-            decompressed_size, crc, isize, crc32
+            "Member {} at {}: {} -> {} bytes",
+            members.len(),
+            start,
+            mem.compressed_size,
+            mem.isize
         );
-        assert_eq!(decompressed_size, isize);
-        assert_eq!(crc, crc32);
-        debug!("\n{}", str::from_utf8(&out).unwrap());
-
-        let mem = GzipMember {
-            flg,
-            mtime,
-            xfl,
-            os,
-            crc16,
-            crc32,
-            isize,
-            offset,
-            file_name,
-            file_comment,
+        members.push(mem);
+    }
+}
+
+/// Describe a gzip file from its first header and the trailer at EOF, without decompressing
+///
+/// This is what `gzip -l` reports: for a file with several members the CRC-32 and
+/// uncompressed size are those of the last member, and nothing is verified.
+/// `offset` is where the first member's data starts, so only a single-member
+/// result can be passed to `extract`.
+pub fn list_last<R: Read + Seek>(mut input: R, file_name: &str) -> Result<GzipMember, Error> {
+    let end = input.seek(SeekFrom::End(0))?;
+    if end < 18 {
+        return Err(Error::new(ErrorKind::Other, "Too short for a gzip member"));
+    }
+    input.seek(SeekFrom::Start(0))?;
+    let mut mem = {
+        let mut reader = BufReader::new(&mut input);
+        let mut mem = read_header(&mut reader, file_name, 0)?;
+        mem.offset = reader.stream_position()?;
+        mem
+    };
+    input.seek(SeekFrom::End(-8))?;
+    let mut dword: [u8; 4] = [0; 4];
+    input.read_exact(&mut dword)?;
+    mem.crc32 = trans_bytes!(dword);
+    input.read_exact(&mut dword)?;
+    mem.isize = trans_bytes!(dword);
+    mem.compressed_size = end;
+    Ok(mem)
+}
+
+/// Decompress every member of a gzip stream into `output`, one after another
+pub fn decompress<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+) -> Result<Vec<GzipMember>, Error> {
+    Ok(decompress_with(input, output, TrailingPolicy::Strict)?.0)
+}
+
+/// `decompress` that deals with data after the last member according to `policy`
+pub fn decompress_with<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    policy: TrailingPolicy,
+) -> Result<(Vec<GzipMember>, TrailingData), Error> {
+    let mut members = Vec::new();
+    loop {
+        let mut mem = match next_member(input, "", members.len(), policy)? {
+            Next::Member(mem) => mem,
+            Next::End(trailing) => return Ok((members, trailing)),
         };
+        let (decompressed_size, crc) = inflate(input, output)?;
+        read_trailer(input, &mut mem, decompressed_size, crc)?;
         members.push(mem);
     }
-    Ok(members)
+}
+
+/// Decompresses a gzip stream as it is read, going on from one member to the next
+///
+/// Each member's CRC-32 and size are checked as its trailer goes by, so the input
+/// doesn't have to be seekable.
+pub struct GzDecoder<R: Read> {
+    input: BufReader<R>,
+    member: Option<GzipMember>,
+    inflater: Option<Inflater>,
+    members: usize,
+    buf: Vec<u8>,
+    pos: usize,
+    policy: TrailingPolicy,
+    trailing: Option<TrailingData>,
+}
+
+impl<R: Read> GzDecoder<R> {
+    pub fn new(input: R) -> GzDecoder<R> {
+        GzDecoder::with_policy(input, TrailingPolicy::Strict)
+    }
+
+    /// Decoder that deals with data after the last member according to `policy`
+    pub fn with_policy(input: R, policy: TrailingPolicy) -> GzDecoder<R> {
+        GzDecoder {
+            input: BufReader::new(input),
+            member: None,
+            inflater: None,
+            members: 0,
+            buf: Vec::new(),
+            pos: 0,
+            policy,
+            trailing: None,
+        }
+    }
+
+    /// What followed the last member, once the end has been reached
+    pub fn trailing(&self) -> Option<TrailingData> {
+        self.trailing
+    }
+
+    /// Header of the member being decompressed, or of the last one once they're all done
+    pub fn header(&self) -> Option<&GzipMember> {
+        self.member.as_ref()
+    }
+
+    /// Number of members fully decompressed and checked so far
+    pub fn members(&self) -> usize {
+        self.members
+    }
+
+    pub fn into_inner(self) -> BufReader<R> {
+        self.input
+    }
+
+    /// Refill `buf`, returning false at the end of the input
+    fn decode_some(&mut self) -> Result<bool, Error> {
+        self.buf.clear();
+        self.pos = 0;
+        if self.trailing.is_some() {
+            return Ok(false);
+        }
+        match self.inflater {
+            None => match next_member(&mut self.input, "", self.members, self.policy)? {
+                Next::Member(mem) => {
+                    self.member = Some(mem);
+                    self.inflater = Some(Inflater::new(&[]));
+                }
+                Next::End(trailing) => {
+                    self.trailing = Some(trailing);
+                    return Ok(false);
+                }
+            },
+            Some(ref mut inflater) => {
+                if inflater.step(&mut self.input, &mut self.buf)? {
+                    let (size, crc) = inflater.size_and_crc();
+                    let member = self.member.as_mut().unwrap();
+                    read_trailer(&mut self.input, member, size, crc)?;
+                    self.inflater = None;
+                    self.members += 1;
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for GzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pos == self.buf.len() {
+            if !self.decode_some()? {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// How `extract_with` names and dates the files it writes
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractOptions {
+    /// Use the name stored in the header, like `gunzip -N`, rather than the gzip
+    /// file's name without `.gz`
+    pub restore_name: bool,
+    /// Set the modification time from the header's MTIME, if it has one
+    pub restore_mtime: bool,
+    /// Fail on a header name with a path separator instead of writing wherever it points
+    pub refuse_paths: bool,
+    /// Replace a file that is already there instead of failing
+    pub overwrite: bool,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> ExtractOptions {
+        ExtractOptions {
+            restore_name: true,
+            restore_mtime: true,
+            refuse_paths: true,
+            overwrite: false,
+        }
+    }
+}
+
+/// Whether a header name stays in the directory it is extracted to
+fn is_plain_name(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name != b"."
+        && name != b".."
+        && !name.contains(&b'/')
+        && !name.contains(&b'\\')
+}
+
+/// FNAME as a file name, byte for byte where the platform allows it
+#[cfg(unix)]
+fn stored_name(member: &GzipMember) -> OsString {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(&member.raw_file_name).to_os_string()
+}
+
+#[cfg(not(unix))]
+fn stored_name(member: &GzipMember) -> OsString {
+    OsString::from(&member.file_name)
 }
 
 pub fn extract(file_name: &str, member: &GzipMember) -> Result<(), Error> {
+    extract_with(file_name, member, &ExtractOptions::default())?;
+    Ok(())
+}
+
+/// Decompress `member` of `file_name` next to it, returning the path written
+pub fn extract_with(
+    file_name: &str,
+    member: &GzipMember,
+    options: &ExtractOptions,
+) -> Result<PathBuf, Error> {
+    let path = if options.restore_name && member.flg.fname {
+        if options.refuse_paths && !is_plain_name(&member.raw_file_name) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!("Refusing file name {:?} from the header", member.file_name),
+            ));
+        }
+        Path::new(file_name).with_file_name(stored_name(member))
+    } else {
+        PathBuf::from(default_name(file_name, member.index))
+    };
+    if path == Path::new(file_name) {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Output would overwrite the input",
+        ));
+    }
+    if !options.overwrite && fs::symlink_metadata(&path).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", path.display()),
+        ));
+    }
+    // Nothing appears at `path` until the trailer checks have passed
+    let mut partial = path.clone().into_os_string();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    let result =
+        extract_to(file_name, member, options, &partial).and_then(|()| fs::rename(&partial, &path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+/// Decompress `member` into `path` and check it against the trailer
+fn extract_to(
+    file_name: &str,
+    member: &GzipMember,
+    options: &ExtractOptions,
+    path: &Path,
+) -> Result<(), Error> {
     let input = File::open(file_name)?;
     let mut reader = BufReader::new(input);
     reader.seek(SeekFrom::Start(member.offset))?;
-    let output = File::create(&member.file_name)?;
+    let output = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)?;
     let mut writer = BufWriter::new(output);
     let (decompressed_size, crc) = inflate(&mut reader, &mut writer)?;
-    assert_eq!(decompressed_size, member.isize);
-    assert_eq!(crc, member.crc32);
+    if crc != member.crc32 {
+        return Err(Error::new(ErrorKind::Other, "Wrong CRC-32"));
+    }
+    if decompressed_size != member.isize {
+        return Err(Error::new(ErrorKind::Other, "Wrong uncompressed size"));
+    }
     writer.flush()?;
+    if options.restore_mtime {
+        if let Some(mtime) = member.mtime() {
+            writer.get_ref().set_modified(mtime)?;
+        }
+    }
     Ok(())
 }
 
+/// Compresses everything written to it into one gzip member
+///
+/// `flush` ends the data so far with a sync flush. The member is completed by
+/// `finish`, or failing that when the writer is dropped, where errors are lost.
+pub struct GzipWriter<W: Write> {
+    // Taken once the trailer is written
+    writer: Option<BitWriter<W>>,
+    options: Options,
+    dict: Vec<u8>,
+    pending: Vec<u8>,
+    crc: Crc32,
+    size: u32,
+}
+
+impl<W: Write> GzipWriter<W> {
+    /// Write the header described by `member` and get ready for the data
+    pub fn new(
+        mut output: W,
+        member: &GzipMember,
+        options: &Options,
+    ) -> Result<GzipWriter<W>, Error> {
+        output.write_all(&member.to_bytes())?;
+        Ok(GzipWriter {
+            writer: Some(BitWriter::from_writer(output, BitOrder::Lsb)),
+            options: *options,
+            dict: Vec::new(),
+            pending: Vec::new(),
+            crc: Crc32::new(),
+            size: 0,
+        })
+    }
+
+    /// The output written to so far
+    pub fn get_ref(&self) -> &W {
+        self.writer.as_ref().unwrap().get_ref()
+    }
+
+    fn compress_pending(&mut self, len: usize, last: bool) -> Result<(), Error> {
+        let data: Vec<u8> = self.pending.drain(..len).collect();
+        let writer = self.writer.as_mut().unwrap();
+        deflate_block(writer, &self.dict, &data, last, &self.options)?;
+        self.dict.extend_from_slice(&data);
+        let excess = self.dict.len().saturating_sub(MAX_DIST);
+        self.dict.drain(..excess);
+        Ok(())
+    }
+
+    fn write_trailer(&mut self) -> Result<W, Error> {
+        let len = self.pending.len();
+        self.compress_pending(len, true)?;
+        let mut output = self.writer.take().unwrap().finish()?;
+        output.write_all(&self.crc.value().to_le_bytes())?;
+        output.write_all(&self.size.to_le_bytes())?;
+        Ok(output)
+    }
+
+    /// Compress what is left, write the trailer and return the output
+    pub fn finish(mut self) -> Result<W, Error> {
+        self.write_trailer()
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.crc.update(buf);
+        self.size = self.size.wrapping_add(buf.len() as u32);
+        self.pending.extend_from_slice(buf);
+        // Hold back a block's worth, as only finish knows which block is last
+        while self.pending.len() > u16::MAX as usize {
+            self.compress_pending(u16::MAX as usize, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Compress what has been written so far and sync flush, so that all of it
+    /// can be decompressed from the output
+    fn flush(&mut self) -> Result<(), Error> {
+        let len = self.pending.len();
+        if len > 0 {
+            self.compress_pending(len, false)?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        if len > 0 || !writer.is_aligned() {
+            sync_flush(writer)?;
+        }
+        writer.flush()
+    }
+}
+
+impl<W: Write> Drop for GzipWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.write_trailer();
+        }
+    }
+}
+
+/// Compress `input` into a single gzip member with the header described by `member`
+pub fn compress<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    member: &GzipMember,
+    options: &Options,
+) -> Result<DeflateStats, Error> {
+    output.write_all(&member.to_bytes())?;
+    let stats = deflate_with(input, output, options)?;
+    output.write_all(&stats.crc32.to_le_bytes())?;
+    output.write_all(&(stats.uncompressed_size as u32).to_le_bytes())?;
+    Ok(stats)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -238,4 +1197,417 @@ mod test {
         let file_name = "test/multiple.gz";
         assert!(parse(&file_name).is_ok());
     }
+
+    fn gunzip(compressed: &[u8]) -> (Vec<GzipMember>, Vec<u8>) {
+        let mut reader = BufReader::new(compressed);
+        let mut out = Vec::new();
+        let members = {
+            let mut writer = BufWriter::new(&mut out);
+            decompress(&mut reader, &mut writer).unwrap()
+        };
+        (members, out)
+    }
+
+    #[test]
+    fn nul_in_header_strings() {
+        let mut member = GzipMember::new();
+        assert!(member.set_file_name("a\0b").is_err());
+        assert!(member.set_comment("\0").is_err());
+        assert!(!member.flg.fname && !member.flg.fcomment);
+        assert_eq!(member.to_bytes().len(), 10);
+    }
+
+    #[test]
+    fn write_header() {
+        let mut member = GzipMember::new();
+        member.set_file_name("notes.txt").unwrap();
+        member.set_comment("hello").unwrap();
+        member.set_mtime(1_600_000_000);
+        member.set_os(OS::UNIX);
+        member.set_extra_flags(ExtraFlags::Maximum);
+        member.set_text(true);
+        member.set_extra(&[b'A', b'B', 1, 0, 7]).unwrap();
+        member.set_header_crc(true);
+        let data = b"Some text\n".repeat(10_000);
+        let mut compressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&data[..]);
+            let mut writer = BufWriter::new(&mut compressed);
+            compress(&mut reader, &mut writer, &member, &Options::level(9)).unwrap();
+        }
+        let (members, out) = gunzip(&compressed);
+        assert_eq!(out, data);
+        let m = &members[0];
+        assert_eq!(m.file_name, "notes.txt");
+        assert_eq!(m.file_comment, "hello");
+        assert_eq!(m.mtime, 1_600_000_000);
+        assert_eq!(m.os, OS::UNIX);
+        assert_eq!(m.xfl, ExtraFlags::Maximum);
+        assert!(m.flg.ftext && m.flg.fhcrc);
+        assert_eq!(
+            m.subfields().unwrap(),
+            [ExtraSubfield::new(b'A', b'B', &[7])]
+        );
+        assert_eq!(m.crc16, crc32(&compressed[..m.to_bytes().len() - 2]) as u16);
+        assert_eq!(m.isize as usize, data.len());
+    }
+
+    #[test]
+    fn unknown_os_and_xfl() {
+        // Empty member from a compressor on OS 19 with XFL 7
+        let mut compressed = vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 7, 19, 3, 0];
+        compressed.extend_from_slice(&[0; 8]);
+        let (members, out) = gunzip(&compressed);
+        assert_eq!(out, b"");
+        assert_eq!(members[0].os(), OS::Other(19));
+        assert_eq!(members[0].compression_hint(), ExtraFlags::Other(7));
+        assert_eq!(members[0].to_bytes(), compressed[..10]);
+        assert_eq!(OS::Other(19).to_string(), "Unknown (19)");
+    }
+
+    #[test]
+    fn writer() {
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 7 + i / 1000) as u8).collect();
+        let mut writer =
+            GzipWriter::new(Vec::new(), &GzipMember::new(), &Options::default()).unwrap();
+        for piece in data.chunks(10_000) {
+            writer.write_all(piece).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        assert!(compressed.len() < data.len() / 10);
+        let (members, out) = gunzip(&compressed);
+        assert_eq!(out, data);
+        assert_eq!(members[0].crc32, crc32(&data));
+
+        let empty = GzipWriter::new(Vec::new(), &GzipMember::new(), &Options::default())
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(gunzip(&empty).1, b"");
+    }
+
+    #[test]
+    fn writer_flush() {
+        let mut writer =
+            GzipWriter::new(Vec::new(), &GzipMember::new(), &Options::default()).unwrap();
+        writer.write_all(b"first line\n").unwrap();
+        writer.flush().unwrap();
+        let flushed = writer.get_ref().clone();
+        assert!(flushed.ends_with(&[0x00, 0x00, 0xFF, 0xFF]));
+        // Everything so far decodes once an empty final block is put after it
+        let mut deflated = flushed[10..].to_vec();
+        deflated.extend_from_slice(&[0x03, 0x00]);
+        let mut out = Vec::new();
+        inflate(
+            &mut BufReader::new(&deflated[..]),
+            &mut BufWriter::new(&mut out),
+        )
+        .unwrap();
+        assert_eq!(out, b"first line\n");
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref().len(), flushed.len());
+
+        writer.write_all(b"second line\n").unwrap();
+        let compressed = writer.finish().unwrap();
+        assert_eq!(gunzip(&compressed).1, b"first line\nsecond line\n");
+    }
+
+    #[test]
+    fn writer_drop() {
+        let mut compressed = Vec::new();
+        {
+            let mut writer =
+                GzipWriter::new(&mut compressed, &GzipMember::new(), &Options::default()).unwrap();
+            writer.write_all(b"finished on drop").unwrap();
+        }
+        assert_eq!(gunzip(&compressed).1, b"finished on drop");
+    }
+
+    #[test]
+    fn extra_subfields() {
+        let dictzip = Subfield::Dictzip {
+            chunk_length: 58_315,
+            chunk_sizes: vec![20_000, 21_000],
+        };
+        let subfields = vec![
+            ExtraSubfield::from(Subfield::Bgzf(1_234)),
+            ExtraSubfield::new(b'X', b'Y', b""),
+            ExtraSubfield::from(dictzip.clone()),
+        ];
+        let mut member = GzipMember::new();
+        member.set_subfields(&subfields).unwrap();
+        let mut compressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&b"abc"[..]);
+            let mut writer = BufWriter::new(&mut compressed);
+            compress(&mut reader, &mut writer, &member, &Options::default()).unwrap();
+        }
+        assert_eq!(&compressed[10..18], &[24, 0, b'B', b'C', 2, 0, 0xD2, 0x04]);
+        let (members, _) = gunzip(&compressed);
+        let read = members[0].subfields().unwrap();
+        assert_eq!(read, subfields);
+        assert_eq!(read[0].decode(), Some(Subfield::Bgzf(1_234)));
+        assert_eq!(read[1].decode(), None);
+        assert_eq!(read[2].decode(), Some(dictzip));
+
+        assert!(parse_extra(&[b'B', b'C', 2, 0, 1]).is_err());
+        assert!(parse_extra(&[b'B', b'C', 2]).is_err());
+        assert!(member.set_extra(&vec![0; 70_000]).is_err());
+    }
+
+    #[test]
+    fn header_crc() {
+        let mut member = GzipMember::new();
+        member.set_file_name("a").unwrap();
+        member.set_header_crc(true);
+        let mut compressed = GzipWriter::new(Vec::new(), &member, &Options::default())
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(gunzip(&compressed).0[0].file_name, "a");
+
+        compressed[10] = b'b';
+        let mut reader = BufReader::new(&compressed[..]);
+        let mut writer = BufWriter::new(Vec::new());
+        let err = decompress(&mut reader, &mut writer).err().unwrap();
+        let crc_err = err.get_ref().unwrap().downcast_ref::<HeaderCrcError>();
+        assert_eq!(crc_err.unwrap().computed, crc32(&compressed[..12]) as u16);
+    }
+
+    #[test]
+    fn listing() {
+        let file_name = "test/multiple.gz";
+        let members = parse(file_name).unwrap();
+        let total: u64 = members.iter().map(|m| m.compressed_size).sum();
+        assert_eq!(total, std::fs::metadata(file_name).unwrap().len());
+        let mut start = 0;
+        for m in &members {
+            assert!(m.offset > start && m.offset < start + m.compressed_size);
+            start += m.compressed_size;
+        }
+
+        let last = list_last(File::open(file_name).unwrap(), file_name).unwrap();
+        let tail = members.last().unwrap();
+        assert_eq!(last.file_name, members[0].file_name);
+        assert_eq!((last.crc32, last.isize), (tail.crc32, tail.isize));
+        assert_eq!(last.compressed_size, total);
+        assert_eq!(last.offset, members[0].offset);
+    }
+
+    #[test]
+    fn accessors() {
+        let mut member = GzipMember::new();
+        member.set_file_name("notes.txt").unwrap();
+        member.set_mtime(1_600_000_000);
+        member.set_os(OS::NTFS);
+        member.set_extra_flags(ExtraFlags::Fastest);
+        member.set_text(true);
+        let data = b"text ".repeat(1000);
+        let mut writer = GzipWriter::new(Vec::new(), &member, &Options::default()).unwrap();
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        let m = &list(&compressed[..], "").unwrap()[0];
+        assert_eq!(m.file_name(), "notes.txt");
+        assert_eq!(m.comment(), "");
+        let since = m.mtime().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(since.as_secs(), 1_600_000_000);
+        assert_eq!(m.os(), OS::NTFS);
+        assert_eq!(m.compression_hint(), ExtraFlags::Fastest);
+        assert!(m.is_text());
+        assert_eq!(m.header_crc(), None);
+        assert_eq!(m.crc32(), crc32(&data));
+        assert_eq!(m.uncompressed_size() as usize, data.len());
+        assert_eq!(m.compressed_size() as usize, compressed.len());
+        assert_eq!(m.offset(), 20);
+        assert_eq!(GzipMember::new().mtime(), None);
+    }
+
+    #[test]
+    fn streaming_decoder() {
+        let mut decoder = GzDecoder::new(File::open("test/multiple.gz").unwrap());
+        let mut out = Vec::new();
+        io::copy(&mut decoder, &mut out).unwrap();
+        assert_eq!(decoder.members(), 2);
+        let mut expected = Vec::new();
+        {
+            let mut reader = BufReader::new(File::open("test/multiple.gz").unwrap());
+            let mut writer = BufWriter::new(&mut expected);
+            decompress(&mut reader, &mut writer).unwrap();
+        }
+        assert_eq!(out, expected);
+
+        // Non-seekable input fed a few bytes at a time, with members big enough to
+        // take several steps each
+        let data: Vec<u8> = (0..200_000u64).map(|i| ((i * i) >> 11) as u8).collect();
+        let mut compressed = Vec::new();
+        for piece in data.chunks(120_000) {
+            let mut writer =
+                GzipWriter::new(Vec::new(), &GzipMember::new(), &Options::default()).unwrap();
+            writer.write_all(piece).unwrap();
+            compressed.extend(writer.finish().unwrap());
+        }
+        let mut decoder = GzDecoder::new(Trickle(&compressed[..]));
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(decoder.members(), 2);
+
+        let last = compressed.len() - 5;
+        compressed[last] ^= 1;
+        let mut decoder = GzDecoder::new(&compressed[..]);
+        assert!(decoder.read_to_end(&mut Vec::new()).is_err());
+    }
+
+    /// Reader that hands out at most 7 bytes at a time
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let n = buf.len().min(7);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn extract_options() {
+        let dir = std::env::temp_dir().join(format!("gzip-extract-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gz = dir.join("archive.gz");
+        let gz_name = gz.to_str().unwrap();
+        let write = |name: &str| {
+            let mut member = GzipMember::new();
+            member.set_file_name(name).unwrap();
+            member.set_mtime(1_500_000_000);
+            let mut writer =
+                GzipWriter::new(File::create(&gz).unwrap(), &member, &Options::default()).unwrap();
+            writer.write_all(b"payload").unwrap();
+            writer.finish().unwrap();
+            parse(gz_name).unwrap().remove(0)
+        };
+
+        let member = write("original.txt");
+        let path = extract_with(gz_name, &member, &ExtractOptions::default()).unwrap();
+        assert_eq!(path, dir.join("original.txt"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"payload");
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        assert_eq!(Some(mtime), member.mtime());
+
+        let err = extract_with(gz_name, &member, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        std::fs::write(&path, b"changed").unwrap();
+        let options = ExtractOptions {
+            overwrite: true,
+            ..ExtractOptions::default()
+        };
+        extract_with(gz_name, &member, &options).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"payload");
+        let last = list_last(File::open(&gz).unwrap(), gz_name).unwrap();
+        extract_with(gz_name, &last, &options).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"payload");
+
+        let options = ExtractOptions {
+            restore_name: false,
+            restore_mtime: false,
+            ..ExtractOptions::default()
+        };
+        let path = extract_with(gz_name, &member, &options).unwrap();
+        assert_eq!(path, dir.join("archive"));
+        assert_ne!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        let mut corrupt = member.clone();
+        corrupt.crc32 ^= 1;
+        std::fs::remove_file(&path).unwrap();
+        let err = extract_with(gz_name, &corrupt, &options).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert!(!path.exists());
+        std::fs::write(dir.join("original.txt"), b"keep").unwrap();
+        let options = ExtractOptions {
+            overwrite: true,
+            ..ExtractOptions::default()
+        };
+        assert!(extract_with(gz_name, &corrupt, &options).is_err());
+        assert_eq!(std::fs::read(dir.join("original.txt")).unwrap(), b"keep");
+        assert!(!dir.join("original.txt.partial").exists());
+
+        for name in &["../escaped", "/tmp/escaped", "a\\b", ".."] {
+            let member = write(name);
+            assert!(extract_with(gz_name, &member, &ExtractOptions::default()).is_err());
+        }
+        assert!(!dir.with_file_name("escaped").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn raw_file_name() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = std::env::temp_dir().join(format!("gzip-raw-name-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gz = dir.join("archive.gz");
+        let gz_name = gz.to_str().unwrap();
+        let mut member = GzipMember::new();
+        member.flg.fname = true;
+        member.raw_file_name = b"caf\xe9.txt".to_vec();
+        member.flg.fcomment = true;
+        member.raw_comment = b"\xa9 1999".to_vec();
+        let mut writer =
+            GzipWriter::new(File::create(&gz).unwrap(), &member, &Options::default()).unwrap();
+        writer.write_all(b"payload").unwrap();
+        writer.finish().unwrap();
+
+        let member = parse(gz_name).unwrap().remove(0);
+        assert_eq!(member.file_name_bytes(), b"caf\xe9.txt");
+        assert_eq!(member.file_name(), "caf\u{fffd}.txt");
+        assert_eq!(member.comment_bytes(), b"\xa9 1999");
+        let path = extract_with(gz_name, &member, &ExtractOptions::default()).unwrap();
+        assert_eq!(path, dir.join(OsStr::from_bytes(b"caf\xe9.txt")));
+        assert_eq!(std::fs::read(&path).unwrap(), b"payload");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trailing_data() {
+        let gz = std::fs::read("test/multiple.gz").unwrap();
+        let members = parse("test/multiple.gz").unwrap();
+        let mut expected = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut expected).unwrap();
+
+        let mut zeros = gz.clone();
+        zeros.extend_from_slice(&[0; 1000]);
+        let mut garbage = gz.clone();
+        garbage.extend_from_slice(b"\x1Fjunk");
+        for (input, trailing) in &[
+            (&gz, TrailingData::Nothing),
+            (&zeros, TrailingData::Zeros(1000)),
+            (&garbage, TrailingData::Garbage(5)),
+        ] {
+            let (listed, t) = list_with(&input[..], "", TrailingPolicy::Tolerant).unwrap();
+            assert_eq!((listed.len(), t), (members.len(), *trailing));
+
+            let mut out = Vec::new();
+            let (_, t) = {
+                let mut reader = BufReader::new(&input[..]);
+                let mut writer = BufWriter::new(&mut out);
+                decompress_with(&mut reader, &mut writer, TrailingPolicy::Tolerant).unwrap()
+            };
+            assert_eq!((&out, t), (&expected, *trailing));
+
+            let mut decoder = GzDecoder::with_policy(&input[..], TrailingPolicy::Tolerant);
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out).unwrap();
+            assert_eq!((&out, decoder.trailing()), (&expected, Some(*trailing)));
+
+            let strict = GzDecoder::new(&input[..]).read_to_end(&mut Vec::new());
+            assert_eq!(strict.is_ok(), *trailing == TrailingData::Nothing);
+        }
+
+        // Garbage in place of the first member is never tolerated, nor is a truncated member
+        assert!(list_with(&b"junk"[..], "", TrailingPolicy::Tolerant).is_err());
+        let truncated = [&gz[..], &gz[..20]].concat();
+        assert!(list_with(&truncated[..], "", TrailingPolicy::Tolerant).is_err());
+    }
 }
@@ -1,6 +1,7 @@
 use std::cmp::{Ordering, PartialOrd};
 use std::collections::BinaryHeap;
-use std::io::{Error, ErrorKind, Read};
+use std::fmt;
+use std::io::{BufRead, Error, ErrorKind, Write};
 use std::u16;
 
 use crate::bitstream::*;
@@ -32,7 +33,9 @@ impl PartialOrd for Char {
     }
 }
 
-#[derive(Debug, Default)]
+/// Canonical Huffman decoding table: the number of codes of each length and
+/// the symbols sorted by code
+#[derive(Clone, Debug, Default)]
 pub struct HuffmanDec {
     count: Vec<u16>,
     symbol: Vec<u16>,
@@ -68,12 +71,23 @@ impl HuffmanDec {
         symbol.append(&mut len9);
         HuffmanDec { count, symbol }
     }
+
+    /// Number of codes of each length, indexed by length
+    pub fn counts(&self) -> &[u16] {
+        &self.count
+    }
+
+    /// Symbols in code order
+    pub fn symbols(&self) -> &[u16] {
+        &self.symbol
+    }
 }
 
 pub struct HuffmanEnc {}
 
 impl HuffmanEnc {
-    pub fn fixed_literal_enc() -> Vec<(Bits, u8)> {
+    /// Code lengths of the fixed literal/length code
+    pub fn fixed_literal_lengths() -> Vec<u8> {
         let mut lit_lens = vec![8 as u8; MAX_NUM_LIT];
         for l in lit_lens.iter_mut().take(256).skip(144) {
             *l = 9;
@@ -81,7 +95,11 @@ impl HuffmanEnc {
         for l in lit_lens.iter_mut().take(280).skip(256) {
             *l = 7;
         }
-        gen_huffman_enc(&lit_lens)
+        lit_lens
+    }
+
+    pub fn fixed_literal_enc() -> Vec<(Bits, u8)> {
+        gen_huffman_enc(&HuffmanEnc::fixed_literal_lengths())
     }
 }
 
@@ -139,7 +157,34 @@ pub fn assign_lengths(v: &[usize]) -> Vec<u8> {
     lengths
 }
 
+/// Assign lengths no longer than `limit` bits
+///
+/// Frequencies are halved until the optimal code fits, which flattens the tree
+/// towards a balanced one. That only ends if there are at most `2^limit` symbols
+/// with a nonzero frequency.
+pub fn assign_limited_lengths(v: &[usize], limit: usize) -> Vec<u8> {
+    let nonzero = v.iter().filter(|f| **f > 0).count();
+    assert!(
+        limit >= usize::BITS as usize || nonzero <= 1 << limit,
+        "{} symbols do not fit in codes of at most {} bits",
+        nonzero,
+        limit
+    );
+    let mut freq = v.to_vec();
+    loop {
+        let lengths = assign_lengths(&freq);
+        if lengths.iter().all(|l| *l as usize <= limit) {
+            return lengths;
+        }
+        for f in freq.iter_mut() {
+            *f -= *f / 2;
+        }
+    }
+}
+
 /// Generate a canonical Huffman encoding table with lengths
+///
+/// Codes come bit reversed, ready for `BitWriter::write_bits` on an LSB-first stream.
 pub fn gen_huffman_enc(v: &[u8]) -> Vec<(Bits, u8)> {
     let max_bits = *v.iter().max().unwrap() as usize;
     let mut bl_count = vec![0 as Bits; max_bits + 1];
@@ -165,9 +210,96 @@ pub fn gen_huffman_enc(v: &[u8]) -> Vec<(Bits, u8)> {
     enc
 }
 
-pub fn gen_huffman_dec(lengths: &[u8], n: u16) -> HuffmanDec {
-    let max_bits = *lengths.iter().max().unwrap() as usize;
-    assert!(max_bits <= MAX_NUM_BITS);
+/// Why a set of code lengths can't make a Huffman code
+///
+/// Comes wrapped in the `io::Error` returned by `check_lengths` and by inflate's
+/// code table reader, where `get_ref` and `downcast_ref` get it back.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CodeLengthError {
+    /// A length over 15 bits
+    TooLong,
+    /// More codes than the lengths have room for
+    OverSubscribed,
+    /// Bit patterns left unused where the format doesn't allow it
+    Incomplete,
+    /// More lengths than the code table announced
+    TooManyLengths,
+    /// A repeat code with no length before it
+    NothingToRepeat,
+    /// More literal/length or distance codes than deflate has
+    TooManyCodes,
+    /// A literal/length code with no end-of-block code
+    NoEndOfBlock,
+}
+
+impl fmt::Display for CodeLengthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            CodeLengthError::TooLong => "Huffman code too long",
+            CodeLengthError::OverSubscribed => "Over-subscribed Huffman code lengths",
+            CodeLengthError::Incomplete => "Incomplete Huffman code lengths",
+            CodeLengthError::TooManyLengths => "Too many code lengths",
+            CodeLengthError::NothingToRepeat => "Repeat with no previous length",
+            CodeLengthError::TooManyCodes => "Too many length or distance codes",
+            CodeLengthError::NoEndOfBlock => "Missing end-of-block code",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl std::error::Error for CodeLengthError {}
+
+impl From<CodeLengthError> for Error {
+    fn from(e: CodeLengthError) -> Error {
+        Error::new(ErrorKind::Other, e)
+    }
+}
+
+/// Check that code lengths describe a usable prefix code, as zlib's `inflate_table` does
+///
+/// Over-subscribed sets are rejected. Incomplete sets are rejected too, except for a
+/// lone one-bit code when `allow_single` is set, which is how a deflate stream
+/// sends a single distance code. A set with no codes at all is accepted.
+pub fn check_lengths(lengths: &[u8], allow_single: bool) -> Result<(), Error> {
+    let mut count = [0 as u16; MAX_NUM_BITS + 1];
+    for l in lengths {
+        if *l as usize > MAX_NUM_BITS {
+            return Err(CodeLengthError::TooLong.into());
+        }
+        count[*l as usize] += 1;
+    }
+    let max_bits = (1..=MAX_NUM_BITS)
+        .rev()
+        .find(|l| count[*l] > 0)
+        .unwrap_or(0);
+    if max_bits == 0 {
+        return Ok(());
+    }
+    // Number of codes still free at each length
+    let mut left: i32 = 1;
+    for c in count.iter().skip(1) {
+        left <<= 1;
+        left -= i32::from(*c);
+        if left < 0 {
+            return Err(CodeLengthError::OverSubscribed.into());
+        }
+    }
+    if left > 0 && !(allow_single && max_bits == 1) {
+        return Err(CodeLengthError::Incomplete.into());
+    }
+    Ok(())
+}
+
+/// Generate a canonical Huffman decoding table for the first `n` symbols of `lengths`
+///
+/// The lengths are checked with `check_lengths`, allowing a single code.
+pub fn gen_huffman_dec(lengths: &[u8], n: u16) -> Result<HuffmanDec, Error> {
+    let lengths = &lengths[..lengths.len().min(n as usize)];
+    check_lengths(lengths, true)?;
+    let max_bits = lengths.iter().max().map_or(0, |l| *l as usize);
+    if max_bits == 0 {
+        return Ok(HuffmanDec::new());
+    }
     let mut count = vec![0 as u16; max_bits + 1];
     for i in lengths {
         if *i != 0 {
@@ -178,28 +310,32 @@ pub fn gen_huffman_dec(lengths: &[u8], n: u16) -> HuffmanDec {
     for (i, c) in count.iter().enumerate().take(max_bits).skip(1) {
         offsets[i + 1] = offsets[i] + *c;
     }
-    //let n = offsets[max_bits+1];//total number of symbols
     let mut symbol = vec![0 as u16; n as usize];
-    for (sym, l) in lengths.iter().enumerate().take(n as usize) {
+    for (sym, l) in lengths.iter().enumerate() {
         let len = *l as usize;
         if len > 0 {
             symbol[offsets[len] as usize] = sym as u16;
             offsets[len] += 1;
         }
     }
-    HuffmanDec { count, symbol }
+    Ok(HuffmanDec { count, symbol })
 }
 
-pub fn read_code<R: Read>(reader: &mut BitReader<R>, dec: &HuffmanDec) -> Result<u16, Error> {
+/// Read one code, most significant bit first
+pub fn read_code<R: BufRead>(reader: &mut BitReader<R>, dec: &HuffmanDec) -> Result<u16, Error> {
     let mut b = 0;
     let mut bits: Bits = 0;
     let mut index = 0;
     let mut first = 0;
-    debug_assert_ne!(dec.count.len(), 1);
-    while b < dec.count.len() {
+    if dec.count.len() < 2 {
+        return Err(Error::new(ErrorKind::Other, "Empty Huffman code"));
+    }
+    // An incomplete code leaves some bit patterns unused, and those run off the
+    // end of the counts instead of matching a length
+    while b + 1 < dec.count.len() {
         let mut e = 1;
         b += 1;
-        while dec.count[b] == 0 {
+        while dec.count[b] == 0 && b + 1 < dec.count.len() {
             e += 1;
             b += 1;
         }
@@ -217,7 +353,85 @@ pub fn read_code<R: Read>(reader: &mut BitReader<R>, dec: &HuffmanDec) -> Result
         index += ct;
         first += ct;
     }
-    Err(Error::new(ErrorKind::Other, "Illegal Huffman code"))
+    Err(Error::new(ErrorKind::Other, "Invalid Huffman code"))
+}
+
+/// Canonical Huffman code over an alphabet of up to 65535 symbols
+///
+/// Codes are at most 15 bits long. Whatever the bit order of the stream, the
+/// first bit sent is the most significant bit of the code, as in deflate and bzip2.
+#[derive(Clone, Debug)]
+pub struct HuffmanCode {
+    lengths: Vec<u8>,
+    codes: Vec<Bits>,
+    dec: HuffmanDec,
+}
+
+impl HuffmanCode {
+    /// Build an optimal code for the symbol frequencies with no code longer than `max_bits`
+    ///
+    /// Symbols with a zero frequency get no code. Panics if more than `2^max_bits`
+    /// symbols have a nonzero frequency.
+    pub fn from_frequencies(freq: &[usize], max_bits: u8) -> HuffmanCode {
+        assert!(max_bits >= 1 && max_bits as usize <= MAX_NUM_BITS);
+        let lengths = if freq.iter().all(|f| *f == 0) {
+            vec![0; freq.len()]
+        } else {
+            assign_limited_lengths(freq, max_bits as usize)
+        };
+        HuffmanCode::from_lengths(&lengths).expect("Optimal code lengths are complete")
+    }
+
+    /// Build the code with the given code length for each symbol, 0 for no code
+    ///
+    /// The lengths must pass `check_lengths` with a single code allowed.
+    pub fn from_lengths(lengths: &[u8]) -> Result<HuffmanCode, Error> {
+        assert!(lengths.len() < u16::MAX as usize);
+        let dec = gen_huffman_dec(lengths, lengths.len() as u16)?;
+        let codes = if dec.count.is_empty() {
+            vec![0; lengths.len()]
+        } else {
+            gen_huffman_enc(lengths)
+                .iter()
+                .map(|(code, len)| if *len > 0 { reverse(*code, *len) } else { 0 })
+                .collect()
+        };
+        Ok(HuffmanCode {
+            lengths: lengths.to_vec(),
+            codes,
+            dec,
+        })
+    }
+
+    /// Code length of each symbol
+    pub fn lengths(&self) -> &[u8] {
+        &self.lengths
+    }
+
+    /// Code of `symbol` and its length, 0 if the symbol has no code
+    pub fn code(&self, symbol: usize) -> (Bits, u8) {
+        (self.codes[symbol], self.lengths[symbol])
+    }
+
+    /// Decoding table of the code
+    pub fn decoder(&self) -> &HuffmanDec {
+        &self.dec
+    }
+
+    /// Write the code of `symbol`
+    pub fn encode<W: Write>(&self, writer: &mut BitWriter<W>, symbol: usize) -> Result<(), Error> {
+        let (code, len) = self.code(symbol);
+        assert!(len > 0, "Symbol {} has no code", symbol);
+        match writer.order() {
+            BitOrder::Lsb => writer.write_bits(reverse(code, len), len),
+            BitOrder::Msb => writer.write_bits(code, len),
+        }
+    }
+
+    /// Read one symbol
+    pub fn decode<R: BufRead>(&self, reader: &mut BitReader<R>) -> Result<u16, Error> {
+        read_code(reader, &self.dec)
+    }
 }
 
 #[cfg(test)]
@@ -284,17 +498,89 @@ mod test {
     fn single_symbol() {
         let code_lens = vec![1];
         //let enc = gen_huffman_enc(&code_lens);
-        let dec = gen_huffman_dec(&code_lens, 1);
+        let dec = gen_huffman_dec(&code_lens, 1).unwrap();
         //error!("{:?}", enc);
         //error!("{:?}", dec);
 
         let mut writer = BitWriter::new();
-        let mut vec = writer.write_bits(0x0, 1);
-        writer.flush().map(|c| {
-            vec.push(c);
-        });
+        writer.write_bits(0x0, 1).unwrap();
+        let vec = writer.finish().unwrap();
         let mut input = BufReader::new(Cursor::new(vec));
         let mut reader = BitReader::new(&mut input);
         let _ = read_code(&mut reader, &dec);
     }
+
+    #[test]
+    fn generic_code() {
+        let freq: Vec<usize> = (0..1000).map(|i| (i * 7919) % 1013).collect();
+        let symbols: Vec<usize> = (0..5000).map(|i| (i * 31) % 1000).collect();
+        let code = HuffmanCode::from_frequencies(&freq, 12);
+        assert!(code.lengths().iter().all(|l| *l <= 12));
+        assert_eq!(code.lengths()[0], 0);
+        for order in [BitOrder::Lsb, BitOrder::Msb] {
+            let mut writer = BitWriter::with_order(order);
+            for s in symbols.iter().filter(|s| freq[**s] > 0) {
+                code.encode(&mut writer, *s).unwrap();
+            }
+            let vec = writer.finish().unwrap();
+            let mut input = BufReader::new(Cursor::new(vec));
+            let mut reader = BitReader::with_order(&mut input, order);
+            for s in symbols.iter().filter(|s| freq[**s] > 0) {
+                assert_eq!(code.decode(&mut reader).unwrap() as usize, *s);
+            }
+        }
+        let code = HuffmanCode::from_lengths(&[2, 1, 3, 3]).unwrap();
+        assert_eq!(code.code(0), (0b10, 2));
+        assert_eq!(code.code(3), (0b111, 3));
+        assert!(HuffmanCode::from_frequencies(&[0, 0], 15)
+            .decode(&mut BitReader::new(&mut Cursor::new(vec![0u8])))
+            .is_err());
+    }
+
+    #[test]
+    fn incomplete_code() {
+        let code = HuffmanCode::from_lengths(&[1]).unwrap();
+        let mut input: &[u8] = &[0b10];
+        let mut reader = BitReader::new(&mut input);
+        assert_eq!(code.decode(&mut reader).unwrap(), 0);
+        let err = code.decode(&mut reader).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid Huffman code");
+
+        let code = HuffmanCode::from_lengths(&[0, 0, 1, 0]).unwrap();
+        let mut input: &[u8] = &[0xFF];
+        let mut reader = BitReader::new(&mut input);
+        assert!(code.decode(&mut reader).is_err());
+    }
+
+    #[test]
+    fn length_limit() {
+        let code = HuffmanCode::from_frequencies(&[1, 0, 1], 1);
+        assert_eq!(code.lengths(), [1, 0, 1]);
+        let code = HuffmanCode::from_frequencies(&[100, 1, 1, 1], 2);
+        assert_eq!(code.lengths(), [2, 2, 2, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "3 symbols do not fit in codes of at most 1 bits")]
+    fn too_many_symbols() {
+        HuffmanCode::from_frequencies(&[1, 1, 1], 1);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let over = gen_huffman_dec(&[1, 1, 1], 3).unwrap_err();
+        assert_eq!(over.to_string(), "Over-subscribed Huffman code lengths");
+        assert_eq!(
+            over.get_ref()
+                .and_then(|e| e.downcast_ref::<CodeLengthError>()),
+            Some(&CodeLengthError::OverSubscribed)
+        );
+        let incomplete = gen_huffman_dec(&[1, 2, 0, 3], 4).unwrap_err();
+        assert_eq!(incomplete.to_string(), "Incomplete Huffman code lengths");
+        assert!(gen_huffman_dec(&[0, 0, 16], 3).is_err());
+        assert!(gen_huffman_dec(&[0, 1, 0], 3).is_ok());
+        assert!(check_lengths(&[0, 1, 0], false).is_err());
+        assert!(check_lengths(&[0, 0, 0], false).is_ok());
+        assert!(check_lengths(&[1, 2, 3, 3], false).is_ok());
+    }
 }
@@ -1,4 +1,3 @@
-extern crate crc;
 #[macro_use]
 extern crate lazy_static;
 #[macro_use]
@@ -7,10 +6,17 @@ extern crate num;
 #[macro_use]
 extern crate num_derive;
 
-mod bitstream;
+pub mod adler32;
+pub mod auto;
+pub mod bgzf;
+pub mod bitstream;
 mod constant;
+pub mod crc32;
 pub mod deflate;
 pub mod huffman;
+mod lz77;
+pub mod parallel;
+pub mod zlib;
 #[macro_use]
 mod util;
 pub mod gzip;
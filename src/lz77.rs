@@ -0,0 +1,241 @@
+use crate::constant::*;
+use crate::deflate::{Finder, Options, LZ77};
+
+const HASH_BITS: usize = 15;
+const HASH_SIZE: usize = 1 << HASH_BITS;
+const HASH_MASK: usize = HASH_SIZE - 1;
+// Three shifts push a byte out of the hash
+const HASH_SHIFT: usize = HASH_BITS.div_ceil(MIN_LEN);
+// Twice the distance, so a chain never wraps onto a position still in reach
+const WINDOW_SIZE: usize = 2 * MAX_DIST;
+const WINDOW_MASK: usize = WINDOW_SIZE - 1;
+const NIL: u32 = u32::MAX;
+
+fn update_hash(hash: usize, byte: u8) -> usize {
+    ((hash << HASH_SHIFT) ^ byte as usize) & HASH_MASK
+}
+
+/// Longest common prefix of the strings at `i` and `j`, starting from `len` known equal bytes
+fn compare(bytes: &[u8], i: usize, j: usize, mut len: usize, limit: usize) -> usize {
+    while len < limit && bytes[i + len] == bytes[j + len] {
+        len += 1;
+    }
+    len
+}
+
+struct MatchFinder<'a> {
+    bytes: &'a [u8],
+    options: &'a Options,
+    hash: usize,
+    head: Vec<u32>,
+    // Previous position in the chain for hash chains, left and right children for binary trees
+    prev: Vec<u32>,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(bytes: &'a [u8], options: &'a Options) -> MatchFinder<'a> {
+        let prev_len = match options.finder {
+            Finder::HashChain => WINDOW_SIZE,
+            Finder::BinaryTree => 2 * WINDOW_SIZE,
+        };
+        let mut hash = 0;
+        for b in bytes.iter().take(MIN_LEN - 1) {
+            hash = update_hash(hash, *b);
+        }
+        MatchFinder {
+            bytes,
+            options,
+            hash,
+            head: vec![NIL; HASH_SIZE],
+            prev: vec![NIL; prev_len],
+        }
+    }
+
+    /// Insert the string at `i`, which must follow the last one inserted, and
+    /// return the (distance, length) of the longest earlier match if asked to
+    fn insert(&mut self, i: usize, find: bool) -> (usize, usize) {
+        self.hash = update_hash(self.hash, self.bytes[i + MIN_LEN - 1]);
+        let candidate = self.head[self.hash];
+        self.head[self.hash] = i as u32;
+        match self.options.finder {
+            Finder::HashChain => {
+                self.prev[i & WINDOW_MASK] = candidate;
+                if find {
+                    self.chain_match(i, candidate)
+                } else {
+                    (0, 0)
+                }
+            }
+            Finder::BinaryTree => self.tree_match(i, candidate),
+        }
+    }
+
+    fn chain_match(&self, i: usize, candidate: u32) -> (usize, usize) {
+        let limit = MAX_LEN.min(self.bytes.len() - i);
+        let mut next = candidate;
+        let mut max_len: usize = 0;
+        let mut max_dist: usize = 0;
+        let mut chain = self.options.max_chain;
+        while next != NIL && chain > 0 {
+            let j = next as usize;
+            if j >= i || i - j > MAX_DIST {
+                break;
+            }
+            // Only a longer match can differ at max_len
+            if self.bytes[j + max_len.min(limit - 1)] == self.bytes[i + max_len.min(limit - 1)] {
+                let len = compare(self.bytes, i, j, 0, limit);
+                if len > max_len {
+                    max_dist = i - j;
+                    max_len = len;
+                    if max_len >= self.options.nice_len.min(limit) {
+                        break;
+                    }
+                }
+            }
+            next = self.prev[j & WINDOW_MASK];
+            chain -= 1;
+        }
+        (max_dist, max_len)
+    }
+
+    /// Search the tree rooted at `candidate` while re-rooting it at `i`
+    fn tree_match(&mut self, i: usize, candidate: u32) -> (usize, usize) {
+        let limit = MAX_LEN.min(self.bytes.len() - i);
+        let mut left_slot = 2 * (i & WINDOW_MASK);
+        let mut right_slot = left_slot + 1;
+        // Bytes known to be shared with everything left and right of the path
+        let mut left_len = 0;
+        let mut right_len = 0;
+        let mut next = candidate;
+        let mut max_len: usize = 0;
+        let mut max_dist: usize = 0;
+        let mut chain = self.options.max_chain;
+        loop {
+            let j = next as usize;
+            if next == NIL || chain == 0 || j >= i || i - j > MAX_DIST {
+                self.prev[left_slot] = NIL;
+                self.prev[right_slot] = NIL;
+                break;
+            }
+            chain -= 1;
+            let node = 2 * (j & WINDOW_MASK);
+            let len = compare(self.bytes, i, j, left_len.min(right_len), limit);
+            if len > max_len {
+                max_len = len;
+                max_dist = i - j;
+            }
+            if len == limit {
+                // Identical strings, so i takes over the node's children
+                self.prev[left_slot] = self.prev[node];
+                self.prev[right_slot] = self.prev[node + 1];
+                break;
+            }
+            if self.bytes[j + len] < self.bytes[i + len] {
+                self.prev[left_slot] = next;
+                left_slot = node + 1;
+                next = self.prev[left_slot];
+                left_len = len;
+            } else {
+                self.prev[right_slot] = next;
+                right_slot = node;
+                next = self.prev[right_slot];
+                right_len = len;
+            }
+        }
+        (max_dist, max_len)
+    }
+}
+
+/// Find LZ77 matches for `bytes[start..]`, with `bytes[..start]` as the dictionary
+pub fn find_matches(bytes: &[u8], start: usize, options: &Options) -> Vec<LZ77> {
+    let len = bytes.len();
+    let incr = if len >= MIN_LEN {
+        len - (MIN_LEN - 1)
+    } else {
+        0
+    };
+    let mut vlz = Vec::<LZ77>::new();
+    let mut finder = MatchFinder::new(bytes, options);
+    for i in 0..start.min(incr) {
+        finder.insert(i, false);
+    }
+    let mut i = start;
+    while i < len {
+        if i < incr {
+            let (max_dist, max_len) = finder.insert(i, true);
+            if max_len >= MIN_LEN {
+                info!("deflate copy {} {}", max_dist, max_len);
+                vlz.push(LZ77::Copy {
+                    len: max_len,
+                    dist: max_dist,
+                });
+                for j in (i + 1)..(i + max_len).min(incr) {
+                    finder.insert(j, false);
+                }
+                i += max_len;
+                continue;
+            }
+        }
+        info!("deflate lit {:02x} at {}", bytes[i], i);
+        vlz.push(LZ77::Literal(u16::from(bytes[i])));
+        i += 1;
+    }
+    vlz
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand(vlz: &[LZ77], dict: &[u8]) -> Vec<u8> {
+        let mut v = dict.to_vec();
+        for lz in vlz {
+            match *lz {
+                LZ77::Literal(l) => v.push(l as u8),
+                LZ77::Copy { len, dist } => {
+                    assert!(dist <= MAX_DIST && (MIN_LEN..=MAX_LEN).contains(&len));
+                    for _ in 0..len {
+                        let b = v[v.len() - dist];
+                        v.push(b);
+                    }
+                }
+            }
+        }
+        v.split_off(dict.len())
+    }
+
+    fn check(level: u8) {
+        let mut bytes = Vec::new();
+        for i in 0..(3 * MAX_DIST) {
+            bytes.push((i * i / 7 % 11) as u8 + b'a');
+        }
+        bytes.extend(vec![b'x'; 1000]);
+        bytes.extend_from_slice(b"abcabcabd");
+        let start = MAX_DIST / 2;
+        let vlz = find_matches(&bytes, start, &Options::level(level));
+        assert!(vlz.len() < bytes.len() - start);
+        assert_eq!(expand(&vlz, &bytes[..start]), &bytes[start..]);
+    }
+
+    #[test]
+    fn hash_chain() {
+        check(1);
+        check(6);
+    }
+
+    #[test]
+    fn binary_tree() {
+        assert_eq!(Options::level(9).finder, Finder::BinaryTree);
+        check(8);
+        check(9);
+    }
+
+    #[test]
+    fn short() {
+        for len in 0..(MIN_LEN + 2) {
+            let bytes = vec![0u8; len];
+            let vlz = find_matches(&bytes, 0, &Options::default());
+            assert_eq!(expand(&vlz, &[]), bytes);
+        }
+    }
+}
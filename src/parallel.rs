@@ -0,0 +1,441 @@
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::mpsc::channel;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::adler32::{adler32, adler32_combine};
+use crate::bitstream::*;
+use crate::constant::*;
+use crate::crc32::{crc32, crc32_combine};
+use crate::deflate::*;
+use crate::gzip::GzipMember;
+use crate::zlib::ZlibHeader;
+
+/// Size of the pieces of input compressed independently
+pub const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Container the compressed stream is wrapped in, with its header
+#[derive(Clone, Copy, Debug)]
+pub enum Format<'a> {
+    Raw,
+    Zlib(ZlibHeader),
+    Gzip(&'a GzipMember),
+}
+
+struct Job {
+    index: usize,
+    dict: Vec<u8>,
+    data: Vec<u8>,
+    // Where the blocks in `data` end
+    ends: Vec<usize>,
+    last: bool,
+}
+
+struct Done {
+    index: usize,
+    compressed: Vec<u8>,
+    blocks: Vec<BlockInfo>,
+    size: u64,
+    crc: u32,
+    adler: u32,
+}
+
+fn compress(job: Job, options: &Options) -> Result<Done, Error> {
+    let mut writer = BitWriter::new();
+    let mut bytes = job.dict;
+    let dict_len = bytes.len();
+    bytes.extend_from_slice(&job.data);
+    let mut blocks = Vec::with_capacity(job.ends.len());
+    let mut start = dict_len;
+    for end in job.ends.iter().map(|e| e + dict_len) {
+        let last = job.last && end == bytes.len();
+        let mut info = deflate_block(
+            &mut writer,
+            &bytes[..start],
+            &bytes[start..end],
+            last,
+            options,
+        )?;
+        // Every block but the very last ends on a byte boundary, so the output is
+        // the same wherever the input was cut into jobs
+        if !last {
+            let before = writer.bit_count();
+            sync_flush(&mut writer)?;
+            info.add_flush_bits(writer.bit_count() - before);
+        }
+        blocks.push(info);
+        start = end;
+    }
+    let compressed = writer.finish()?;
+    Ok(Done {
+        index: job.index,
+        compressed,
+        blocks,
+        size: job.data.len() as u64,
+        crc: crc32(&job.data),
+        adler: adler32(&job.data),
+    })
+}
+
+fn write_header<W: Write>(output: &mut W, format: Format) -> Result<(), Error> {
+    match format {
+        Format::Raw => Ok(()),
+        Format::Zlib(header) => {
+            if header.dict_id.is_some() {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "Preset dictionaries are not supported",
+                ));
+            }
+            output.write_all(&header.to_bytes())
+        }
+        Format::Gzip(member) => output.write_all(&member.to_bytes()),
+    }
+}
+
+fn write_trailer<W: Write>(
+    output: &mut W,
+    format: Format,
+    len: u64,
+    crc: u32,
+    adler: u32,
+) -> Result<(), Error> {
+    match format {
+        Format::Raw => Ok(()),
+        Format::Zlib(_) => output.write_all(&adler.to_be_bytes()),
+        Format::Gzip(_) => {
+            output.write_all(&crc.to_le_bytes())?;
+            output.write_all(&(len as u32).to_le_bytes())
+        }
+    }
+}
+
+/// Compress `input` on `threads` threads, pigz style
+///
+/// The input is split into `CHUNK_SIZE` pieces which are deflated independently,
+/// each primed with the last 32K of the input before it. Every piece but the last
+/// ends with a sync flush so the pieces join into one stream. With
+/// `Options::rsyncable` the pieces are cut where `deflate_with` would end its
+/// blocks instead, and every block ends with a sync flush.
+///
+/// The compressed size in the returned stats leaves out the zlib or gzip wrapper.
+pub fn deflate_parallel<R: Read, W: Write>(
+    input: &mut R,
+    output: &mut W,
+    format: Format,
+    threads: usize,
+    options: &Options,
+) -> Result<DeflateStats, Error> {
+    let threads = threads.max(1);
+    let (job_tx, job_rx) = channel::<Job>();
+    let (done_tx, done_rx) = channel::<Result<Done, Error>>();
+    let job_rx = Mutex::new(job_rx);
+    write_header(output, format)?;
+    let mut stats = DeflateStats::default();
+    let mut adler: u32 = 1;
+    thread::scope(|s| {
+        for _ in 0..threads {
+            let job_rx = &job_rx;
+            let done_tx = done_tx.clone();
+            s.spawn(move || loop {
+                let job = match job_rx.lock() {
+                    Ok(rx) => rx.recv(),
+                    Err(_) => return,
+                };
+                match job {
+                    Ok(job) => {
+                        if done_tx.send(compress(job, options)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(_) => return,
+                }
+            });
+        }
+        drop(done_tx);
+
+        let mut pending = BTreeMap::<usize, Done>::new();
+        let mut next_index = 0;
+        let mut in_flight = 0;
+        let mut collect = |block: bool, in_flight: &mut usize| -> Result<(), Error> {
+            let done = if block {
+                done_rx.recv().ok()
+            } else {
+                done_rx.try_recv().ok()
+            };
+            if let Some(done) = done {
+                *in_flight -= 1;
+                let done = done?;
+                let _ = pending.insert(done.index, done);
+            } else if block {
+                return Err(Error::new(ErrorKind::Other, "Compression thread died"));
+            }
+            while let Some(done) = pending.remove(&next_index) {
+                output.write_all(&done.compressed)?;
+                stats.crc32 = crc32_combine(stats.crc32, done.crc, done.size);
+                adler = adler32_combine(adler, done.adler, done.size);
+                stats.uncompressed_size += done.size;
+                stats.compressed_size += done.compressed.len() as u64;
+                stats.blocks.extend(done.blocks);
+                next_index += 1;
+            }
+            Ok(())
+        };
+
+        let max_block = if options.rsyncable {
+            u16::MAX as usize
+        } else {
+            CHUNK_SIZE
+        };
+        let mut block_ends = BlockEnds::new(options.rsyncable, max_block);
+        let mut dict = Vec::<u8>::new();
+        // Input read but not in a job yet
+        let mut unsent = Vec::<u8>::new();
+        let mut data = read_chunk(input, CHUNK_SIZE)?;
+        let mut index = 0;
+        loop {
+            let next = read_chunk(input, CHUNK_SIZE)?;
+            let last = next.is_empty();
+            let ends = block_ends.next(&data, last);
+            unsent.extend_from_slice(&data);
+            if let Some(&(end, _)) = ends.last() {
+                let data: Vec<u8> = unsent.drain(..end).collect();
+                // The last 32K of input before the next job
+                let keep = MAX_DIST.saturating_sub(data.len()).min(dict.len());
+                let mut next_dict = dict[dict.len() - keep..].to_vec();
+                next_dict.extend_from_slice(&data[data.len() - data.len().min(MAX_DIST)..]);
+                let job = Job {
+                    index,
+                    dict,
+                    data,
+                    ends: ends.iter().map(|e| e.0).collect(),
+                    last,
+                };
+                if job_tx.send(job).is_err() {
+                    return Err(Error::new(ErrorKind::Other, "Compression thread died"));
+                }
+                in_flight += 1;
+                index += 1;
+                dict = next_dict;
+            }
+            while in_flight >= 2 * threads {
+                collect(true, &mut in_flight)?;
+            }
+            collect(false, &mut in_flight)?;
+            if last {
+                break;
+            }
+            data = next;
+        }
+        drop(job_tx);
+        while in_flight > 0 {
+            collect(true, &mut in_flight)?;
+        }
+        Ok(())
+    })?;
+    write_trailer(output, format, stats.uncompressed_size, stats.crc32, adler)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufReader, BufWriter};
+
+    use crate::gzip::GzDecoder;
+
+    use rand::{self, Rng};
+
+    fn sample(len: usize) -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        let words: Vec<Vec<u8>> = (0..500)
+            .map(|_| {
+                let n = rng.gen_range(1, 12);
+                (0..n).map(|_| rng.gen_range(b'a', b'z' + 1)).collect()
+            })
+            .collect();
+        let mut v = Vec::with_capacity(len + 300);
+        while v.len() < len {
+            if rng.gen_range(0, 100) == 0 {
+                v.extend(vec![b' '; rng.gen_range(1, 300)]);
+            }
+            v.extend_from_slice(&words[rng.gen_range(0, words.len())]);
+            v.push(b' ');
+        }
+        v.truncate(len);
+        v
+    }
+
+    fn round_trip(uncompressed: &[u8], format: Format) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let stats = deflate_parallel(
+            &mut &uncompressed[..],
+            &mut compressed,
+            format,
+            4,
+            &Options::default(),
+        )
+        .unwrap();
+        let crc = crc32(uncompressed);
+        assert_eq!(stats.uncompressed_size, uncompressed.len() as u64);
+        assert_eq!(stats.crc32, crc);
+        assert_eq!(
+            stats.blocks.len(),
+            uncompressed.len().div_ceil(CHUNK_SIZE).max(1)
+        );
+        let (header, trailer) = match format {
+            Format::Raw => (0, 0),
+            Format::Zlib(header) => (header.to_bytes().len(), 4),
+            Format::Gzip(member) => (member.to_bytes().len(), 8),
+        };
+        let mut decompressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&compressed[header..compressed.len() - trailer]);
+            let mut writer = BufWriter::new(&mut decompressed);
+            let (decompressed_len, dcrc) = inflate(&mut reader, &mut writer).unwrap();
+            assert_eq!(decompressed_len as usize, uncompressed.len());
+            assert_eq!(dcrc, crc);
+        }
+        assert_eq!(decompressed, uncompressed);
+        compressed
+    }
+
+    #[test]
+    fn raw() {
+        round_trip(&[], Format::Raw);
+        round_trip(&sample(CHUNK_SIZE * 3 + 1234), Format::Raw);
+    }
+
+    #[test]
+    fn stats() {
+        let uncompressed = sample(CHUNK_SIZE * 2 + 77);
+        let mut compressed = Vec::new();
+        let stats = deflate_parallel(
+            &mut &uncompressed[..],
+            &mut compressed,
+            Format::Raw,
+            3,
+            &Options::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.compressed_size, compressed.len() as u64);
+        assert_eq!(stats.crc32, crc32(&uncompressed));
+        let total_bits: u64 = stats.blocks.iter().map(|b| b.compressed_bits).sum();
+        assert_eq!(total_bits.div_ceil(8), stats.compressed_size);
+    }
+
+    #[test]
+    fn report() {
+        let uncompressed = sample(CHUNK_SIZE * 2 + 77);
+        let options = Options {
+            report: true,
+            ..Options::default()
+        };
+        let mut compressed = Vec::new();
+        let stats = deflate_parallel(
+            &mut &uncompressed[..],
+            &mut compressed,
+            Format::Raw,
+            2,
+            &options,
+        )
+        .unwrap();
+        for block in &stats.blocks {
+            let report = block.report.as_ref().unwrap();
+            assert_eq!(
+                report.header_bits + report.literal_bits + report.match_bits,
+                block.compressed_bits
+            );
+        }
+    }
+
+    #[test]
+    fn gzip() {
+        let uncompressed = sample(CHUNK_SIZE + 10);
+        let mut member = GzipMember::new();
+        member.set_file_name("sample.txt").unwrap();
+        member.set_mtime(1_600_000_000);
+        let compressed = round_trip(&uncompressed, Format::Gzip(&member));
+        assert_eq!(compressed[..member.to_bytes().len()], member.to_bytes()[..]);
+        let trailer = &compressed[compressed.len() - 8..];
+        assert_eq!(trailer[0..4], crc32(&uncompressed).to_le_bytes());
+        assert_eq!(trailer[4..8], (uncompressed.len() as u32).to_le_bytes());
+        let mut decoder = GzDecoder::new(&compressed[..]);
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, uncompressed);
+        assert_eq!(decoder.header().unwrap().file_name(), "sample.txt");
+    }
+
+    #[test]
+    fn zlib() {
+        let uncompressed = sample(2 * CHUNK_SIZE);
+        let compressed = round_trip(&uncompressed, Format::Zlib(ZlibHeader::for_level(6)));
+        assert_eq!(compressed[..2], [0x78, 0x9C]);
+        let trailer = &compressed[compressed.len() - 4..];
+        assert_eq!(trailer, adler32(&uncompressed).to_be_bytes());
+    }
+
+    #[test]
+    fn zlib_level() {
+        let mut compressed = Vec::new();
+        deflate_parallel(
+            &mut &b"hello"[..],
+            &mut compressed,
+            Format::Zlib(ZlibHeader::for_level(9)),
+            1,
+            &Options::level(9),
+        )
+        .unwrap();
+        assert_eq!(compressed[..2], [0x78, 0xDA]);
+
+        let header = ZlibHeader {
+            dict_id: Some(1),
+            ..ZlibHeader::for_level(6)
+        };
+        let format = Format::Zlib(header);
+        let result = deflate_parallel(
+            &mut &b"hello"[..],
+            &mut Vec::new(),
+            format,
+            1,
+            &Options::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rsyncable() {
+        let mut rng = rand::thread_rng();
+        let uncompressed: Vec<u8> = (0..600_000).map(|_| rng.gen_range(b'a', b'h')).collect();
+        let options = Options {
+            rsyncable: true,
+            ..Options::default()
+        };
+        let compress = |data: &[u8]| {
+            let mut compressed = Vec::new();
+            deflate_parallel(&mut &data[..], &mut compressed, Format::Raw, 3, &options).unwrap();
+            let mut decompressed = Vec::new();
+            inflate(
+                &mut BufReader::new(&compressed[..]),
+                &mut BufWriter::new(&mut decompressed),
+            )
+            .unwrap();
+            assert_eq!(decompressed, data);
+            compressed
+        };
+        let before = compress(&uncompressed);
+        let mut edited = uncompressed[..70_000].to_vec();
+        edited.extend_from_slice(b"inserted text");
+        edited.extend_from_slice(&uncompressed[70_000..]);
+        let after = compress(&edited);
+        let common = before
+            .iter()
+            .rev()
+            .zip(after.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        assert!(common > after.len() / 2);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::fmt;
 use std::fs::{self, File};
 use std::io::prelude::*;
@@ -8,9 +9,9 @@ use std::str;
 use std::string::String;
 use std::vec::Vec;
 
-use crc::crc32::{Digest, Hasher32, IEEE};
 use num::FromPrimitive;
 
+use crate::crc32::Crc32;
 use crate::deflate::*;
 
 #[repr(u32)]
@@ -485,11 +486,99 @@ pub fn extract(file_name: &str, lfh: &LocalFileHeader) -> Result<(), Error> {
     debug!("File");
     let out = File::create(&lfh.file_name)?;
     let mut writer = BufWriter::new(out);
-    match lfh.compression_method {
+    decode_entry(&mut reader, &mut writer, lfh)?;
+    writer.flush()?;
+    Ok(())
+}
+
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+
+/// CRC-32 and sizes that follow the data when general purpose bit 3 is set
+struct DataDescriptor {
+    crc: u32,
+    compressed_size: u32,
+    uncompressed_size: u32,
+}
+
+impl DataDescriptor {
+    fn from_bytes(a: &[u8]) -> DataDescriptor {
+        let field = |i: usize| u32::from_le_bytes([a[i], a[i + 1], a[i + 2], a[i + 3]]);
+        DataDescriptor {
+            crc: field(0),
+            compressed_size: field(4),
+            uncompressed_size: field(8),
+        }
+    }
+}
+
+/// Read a data descriptor, whose signature may or may not be there
+fn read_data_descriptor<R: Read>(reader: &mut R) -> Result<DataDescriptor, Error> {
+    let mut a = [0u8; 16];
+    reader.read_exact(&mut a[..12])?;
+    if u32::from_le_bytes([a[0], a[1], a[2], a[3]]) == DATA_DESCRIPTOR_SIGNATURE {
+        reader.read_exact(&mut a[12..])?;
+        return Ok(DataDescriptor::from_bytes(&a[4..]));
+    }
+    Ok(DataDescriptor::from_bytes(&a[..12]))
+}
+
+/// Copy stored data whose size only comes in the data descriptor after it
+///
+/// The end is found by looking for a signed descriptor that matches the data
+/// copied so far, so a descriptor without its signature can't be told apart.
+fn copy_to_descriptor<R: Read, W: Write>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+) -> Result<(u32, u32), Error> {
+    let mut window = VecDeque::<u8>::with_capacity(16);
+    let mut copied: u32 = 0;
+    let mut hasher = Crc32::new();
+    let mut byte = [0u8; 1];
+    loop {
+        if window.len() == 16 {
+            let bytes = window.make_contiguous();
+            let signature = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            if signature == DATA_DESCRIPTOR_SIGNATURE {
+                let descriptor = DataDescriptor::from_bytes(&bytes[4..]);
+                if descriptor.crc == hasher.value()
+                    && descriptor.compressed_size == copied
+                    && descriptor.uncompressed_size == copied
+                {
+                    return Ok((copied, hasher.value()));
+                }
+            }
+            if let Some(first) = window.pop_front() {
+                writer.write_all(&[first])?;
+                hasher.update(&[first]);
+                copied += 1;
+            }
+        }
+        if reader.read(&mut byte)? == 0 {
+            return Err(Error::new(ErrorKind::Other, "Missing data descriptor"));
+        }
+        window.push_back(byte[0]);
+    }
+}
+
+/// Decode the data of the entry described by `lfh`, which `reader` is positioned at
+///
+/// With a data descriptor, the descriptor after the data is read and checked too.
+fn decode_entry<R: Read, W: Write>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    lfh: &LocalFileHeader,
+) -> Result<(), Error> {
+    // With a data descriptor the sizes and CRC only come after the data
+    let described = lfh.general_purpose_bit_flag.crc;
+    let (size, checksum) = match lfh.compression_method {
+        CompMethod::Store if described => {
+            copy_to_descriptor(reader, writer)?;
+            return Ok(());
+        }
         CompMethod::Store => {
-            let mut out = vec![0 as u8; 64 * 1024];
+            let mut out = vec![0u8; 64 * 1024];
             let mut copied = 0;
-            let mut hasher = Digest::new(IEEE);
+            let mut hasher = Crc32::new();
             while copied < lfh.uncompressed_size {
                 let to_copy = (lfh.uncompressed_size - copied) as usize;
                 if to_copy < out.len() {
@@ -498,26 +587,56 @@ pub fn extract(file_name: &str, lfh: &LocalFileHeader) -> Result<(), Error> {
                 reader.read_exact(&mut out)?;
                 writer.write_all(&out)?;
                 copied += out.len() as u32;
-                hasher.write(&out);
+                hasher.update(&out);
             }
-            assert_eq!(hasher.sum32(), lfh.crc);
-        }
-        CompMethod::Deflate => {
-            let (decompressed_size, checksum) = inflate(&mut reader, &mut writer)?;
-            assert_eq!(decompressed_size, lfh.uncompressed_size);
-            assert_eq!(checksum, lfh.crc);
+            (copied, hasher.value())
         }
+        CompMethod::Deflate => inflate(reader, writer)?,
         _ => {
             return Err(Error::new(
                 ErrorKind::Other,
                 "Unsupported compression method",
             ))
         }
+    };
+    let (crc, uncompressed_size) = if described {
+        let descriptor = read_data_descriptor(reader)?;
+        (descriptor.crc, descriptor.uncompressed_size)
+    } else {
+        (lfh.crc, lfh.uncompressed_size)
+    };
+    if checksum != crc {
+        return Err(Error::new(ErrorKind::Other, "Wrong CRC-32"));
+    }
+    if size != uncompressed_size {
+        return Err(Error::new(ErrorKind::Other, "Wrong uncompressed size"));
     }
-    writer.flush()?;
     Ok(())
 }
 
+/// Decompress the entry whose local file header `input` starts with
+pub fn decompress<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+) -> Result<LocalFileHeader, Error> {
+    let mut dword: [u8; 4] = [0; 4];
+    let mut lfh_array: [u8; LFH_SIZE] = [0; LFH_SIZE];
+    input.read_exact(&mut dword)?;
+    match Signature::from_u32(trans_bytes!(dword)) {
+        Some(Signature::LFH) => {}
+        _ => return Err(Error::new(ErrorKind::Other, "Not a local file header")),
+    }
+    input.read_exact(&mut lfh_array)?;
+    let mut lfh = read_lfh(lfh_array)?;
+    let mut v = vec![0u8; lfh.file_name_length as usize];
+    input.read_exact(&mut v)?;
+    lfh.file_name = String::from_utf8_lossy(&v).into_owned();
+    let mut extra = vec![0u8; lfh.extra_field_length as usize];
+    input.read_exact(&mut extra)?;
+    decode_entry(input, output, &lfh)?;
+    Ok(lfh)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -536,4 +655,58 @@ mod test {
     fn dynamic_huffman() {
         assert!(parse("test/dynamic_huffman.zip").is_ok());
     }
+
+    /// A local file entry for `data`, with a signed data descriptor after it
+    fn described_entry(method: u16, data: &[u8], crc: u32, size: u32) -> Vec<u8> {
+        let compressed = if method == 8 {
+            let mut out = Vec::new();
+            deflate(&mut BufReader::new(data), &mut BufWriter::new(&mut out)).unwrap();
+            out
+        } else {
+            data.to_vec()
+        };
+        let mut v = Vec::new();
+        v.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        v.extend_from_slice(&[20, 0, 1 << 3, 0]);
+        v.extend_from_slice(&method.to_le_bytes());
+        v.extend_from_slice(&[0; 4 + 4 * 3]);
+        v.extend_from_slice(&1u16.to_le_bytes());
+        v.extend_from_slice(&0u16.to_le_bytes());
+        v.push(b'a');
+        v.extend_from_slice(&compressed);
+        v.extend_from_slice(&DATA_DESCRIPTOR_SIGNATURE.to_le_bytes());
+        v.extend_from_slice(&crc.to_le_bytes());
+        v.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        v.extend_from_slice(&size.to_le_bytes());
+        v
+    }
+
+    fn decompress_entry(entry: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        decompress(&mut BufReader::new(entry), &mut BufWriter::new(&mut out))?;
+        Ok(out)
+    }
+
+    #[test]
+    fn data_descriptor() {
+        let data = b"PK\x07\x08 inside the data, PK\x07\x08 and again".repeat(10);
+        let crc = crate::crc32::crc32(&data);
+        let len = data.len() as u32;
+        for &method in &[0, 8] {
+            let entry = described_entry(method, &data, crc, len);
+            assert_eq!(decompress_entry(&entry).unwrap(), data);
+        }
+        assert!(decompress_entry(&described_entry(8, &data, crc ^ 1, len)).is_err());
+        assert!(decompress_entry(&described_entry(8, &data, crc, len + 1)).is_err());
+        assert!(decompress_entry(&described_entry(0, &data, crc ^ 1, len)).is_err());
+    }
+
+    #[test]
+    fn unsigned_data_descriptor() {
+        let data = b"no signature".to_vec();
+        let mut entry = described_entry(8, &data, crate::crc32::crc32(&data), 12);
+        let end = entry.len() - 12;
+        entry.drain(end - 4..end);
+        assert_eq!(decompress_entry(&entry).unwrap(), data);
+    }
 }
@@ -0,0 +1,246 @@
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+use crate::adler32::{adler32, Adler32};
+use crate::deflate::*;
+
+/// Compression method of every zlib stream
+const CM_DEFLATE: u8 = 8;
+/// Largest CINFO, for a 32K window
+const MAX_CINFO: u8 = 7;
+const FDICT: u8 = 0x20;
+
+/// The CMF and FLG bytes of a zlib stream, with the dictionary id if FDICT is set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZlibHeader {
+    /// Base-2 logarithm of the window size, minus eight
+    pub cinfo: u8,
+    /// How hard the compressor tried, from 0 (fastest) to 3 (maximum)
+    pub flevel: u8,
+    /// Adler-32 of the preset dictionary the stream needs
+    pub dict_id: Option<u32>,
+}
+
+impl ZlibHeader {
+    /// Header for a 32K window at compression `level` 0-9, mapped to FLEVEL as zlib does
+    pub fn for_level(level: u8) -> ZlibHeader {
+        let flevel = match level {
+            0..=1 => 0,
+            2..=5 => 1,
+            6 => 2,
+            _ => 3,
+        };
+        ZlibHeader {
+            cinfo: MAX_CINFO,
+            flevel,
+            dict_id: None,
+        }
+    }
+
+    pub fn window_size(&self) -> usize {
+        1 << (self.cinfo + 8)
+    }
+
+    /// Encode the header, setting FCHECK so CMF * 256 + FLG is a multiple of 31
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let cmf = self.cinfo << 4 | CM_DEFLATE;
+        let mut flg = self.flevel << 6;
+        if self.dict_id.is_some() {
+            flg |= FDICT;
+        }
+        flg += ((31 - (u16::from(cmf) << 8 | u16::from(flg)) % 31) % 31) as u8;
+        let mut v = vec![cmf, flg];
+        if let Some(id) = self.dict_id {
+            v.extend_from_slice(&id.to_be_bytes());
+        }
+        v
+    }
+}
+
+/// Read and check a zlib header
+pub fn read_header<R: Read>(input: &mut R) -> Result<ZlibHeader, Error> {
+    let mut bytes = [0u8; 2];
+    input.read_exact(&mut bytes)?;
+    let (cmf, flg) = (bytes[0], bytes[1]);
+    if (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err(Error::new(ErrorKind::Other, "Bad zlib header check bits"));
+    }
+    if cmf & 0x0F != CM_DEFLATE {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Unsupported zlib compression method",
+        ));
+    }
+    let cinfo = cmf >> 4;
+    if cinfo > MAX_CINFO {
+        return Err(Error::new(ErrorKind::Other, "Bad zlib window size"));
+    }
+    let dict_id = if flg & FDICT == FDICT {
+        let mut id = [0u8; 4];
+        input.read_exact(&mut id)?;
+        Some(u32::from_be_bytes(id))
+    } else {
+        None
+    };
+    Ok(ZlibHeader {
+        cinfo,
+        flevel: flg >> 6,
+        dict_id,
+    })
+}
+
+/// Passes bytes through while keeping their Adler-32
+struct Adler<T> {
+    inner: T,
+    sum: Adler32,
+}
+
+impl<T> Adler<T> {
+    fn new(inner: T) -> Adler<T> {
+        Adler {
+            inner,
+            sum: Adler32::new(),
+        }
+    }
+}
+
+impl<T: Read> Read for Adler<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.inner.read(buf)?;
+        self.sum.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for Adler<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = self.inner.write(buf)?;
+        self.sum.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+/// Decompress a zlib stream, returning the header and the decompressed size
+pub fn decompress<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+) -> Result<(ZlibHeader, u32), Error> {
+    decompress_with_dict(input, output, &[])
+}
+
+/// Decompress a zlib stream that may need the preset dictionary `dict`
+pub fn decompress_with_dict<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    dict: &[u8],
+) -> Result<(ZlibHeader, u32), Error> {
+    let header = read_header(input)?;
+    let dict: &[u8] = match header.dict_id {
+        Some(id) if dict.is_empty() || adler32(dict) != id => {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Missing zlib preset dictionary",
+            ));
+        }
+        Some(_) => dict,
+        None => &[],
+    };
+    let mut checked = BufWriter::new(Adler::new(&mut *output));
+    let (size, _) = inflate_with_dict(input, &mut checked, dict)?;
+    checked.flush()?;
+    let sum = match checked.into_inner() {
+        Ok(x) => x.sum.value(),
+        Err(_) => return Err(Error::new(ErrorKind::Other, "Can't get the inner output")),
+    };
+    let mut trailer = [0u8; 4];
+    input.read_exact(&mut trailer)?;
+    if u32::from_be_bytes(trailer) != sum {
+        return Err(Error::new(ErrorKind::Other, "Wrong Adler-32"));
+    }
+    Ok((header, size))
+}
+
+/// Compress `input` into a zlib stream at `level` 0-9
+pub fn compress<R: Read, W: Write>(
+    input: &mut BufReader<R>,
+    output: &mut BufWriter<W>,
+    level: u8,
+) -> Result<DeflateStats, Error> {
+    output.write_all(&ZlibHeader::for_level(level).to_bytes())?;
+    let mut checked = BufReader::new(Adler::new(input));
+    let stats = deflate_with(&mut checked, output, &Options::level(level))?;
+    output.write_all(&checked.get_ref().sum.value().to_be_bytes())?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header() {
+        // The usual headers for levels 1, 6 and 9
+        assert_eq!(ZlibHeader::for_level(1).to_bytes(), [0x78, 0x01]);
+        assert_eq!(ZlibHeader::for_level(6).to_bytes(), [0x78, 0x9C]);
+        assert_eq!(ZlibHeader::for_level(9).to_bytes(), [0x78, 0xDA]);
+        let header = read_header(&mut &[0x78u8, 0xDA][..]).unwrap();
+        assert_eq!(header.window_size(), 32768);
+        assert_eq!(header.flevel, 3);
+        assert!(read_header(&mut &[0x78u8, 0xDB][..]).is_err());
+        assert!(read_header(&mut &[0x79u8, 0x96][..]).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let uncompressed: Vec<u8> = (0..100_000u64).map(|i| (i * i % 251) as u8).collect();
+        let mut compressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&uncompressed[..]);
+            let mut writer = BufWriter::new(&mut compressed);
+            compress(&mut reader, &mut writer, 9).unwrap();
+        }
+        assert_eq!(
+            compressed[compressed.len() - 4..],
+            adler32(&uncompressed).to_be_bytes()
+        );
+        let mut decompressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&compressed[..]);
+            let mut writer = BufWriter::new(&mut decompressed);
+            let (header, size) = decompress(&mut reader, &mut writer).unwrap();
+            assert_eq!(header, ZlibHeader::for_level(9));
+            assert_eq!(size as usize, uncompressed.len());
+        }
+        assert_eq!(decompressed, uncompressed);
+
+        let last = compressed.len() - 1;
+        compressed[last] ^= 1;
+        let mut reader = BufReader::new(&compressed[..]);
+        let mut writer = BufWriter::new(Vec::new());
+        assert!(decompress(&mut reader, &mut writer).is_err());
+    }
+
+    #[test]
+    fn preset_dictionary() {
+        // zlib.compressobj(zdict=b"hello world, hello").compress(b"hello world!") + flush()
+        let dict = b"hello world, hello";
+        let compressed = [
+            0x78, 0xBB, 0x40, 0xB1, 0x06, 0xBD, 0xCB, 0x40, 0x88, 0x28, 0x02, 0x00, 0x1E, 0x89,
+            0x04, 0x7E,
+        ];
+        let mut decompressed = Vec::new();
+        {
+            let mut reader = BufReader::new(&compressed[..]);
+            let mut writer = BufWriter::new(&mut decompressed);
+            let (header, _) = decompress_with_dict(&mut reader, &mut writer, &dict[..]).unwrap();
+            assert_eq!(header.dict_id, Some(adler32(dict)));
+        }
+        assert_eq!(decompressed, b"hello world!");
+        let mut reader = BufReader::new(&compressed[..]);
+        let mut writer = BufWriter::new(Vec::new());
+        assert!(decompress(&mut reader, &mut writer).is_err());
+    }
+}